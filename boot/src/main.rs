@@ -30,6 +30,9 @@ const TEST_ARGS: &[&str] = &[
     "none",
     "--no-reboot",
 ];
+/// Exposes COM2, where the kernel's GDB stub listens, on a TCP port:
+/// `target remote localhost:1235` from GDB.
+const GDB_STUB_ARGS: &[&str] = &["-serial", "tcp::1235,server,nowait"];
 const TEST_TIMEOUT_SECS: u64 = 10;
 
 fn main() {
@@ -39,14 +42,15 @@ fn main() {
         let path = PathBuf::from_iter(["../", args.next().as_ref().unwrap()]);
         path.canonicalize().unwrap()
     };
-    let no_boot = if let Some(arg) = args.next() {
+    let mut no_boot = false;
+    let mut gdb_stub = false;
+    for arg in args {
         match arg.as_str() {
-            "--no-run" => true,
+            "--no-run" => no_boot = true,
+            "--gdb" => gdb_stub = true,
             other => panic!("unexpected argument `{}`", other),
         }
-    } else {
-        false
-    };
+    }
 
     let binary_kind = runner_utils::binary_kind(&kernel_binary_path);
     let bios = create_disk_images(&kernel_binary_path, binary_kind.is_test());
@@ -71,6 +75,9 @@ fn main() {
         }
     } else {
         run_cmd.args(RUN_ARGS);
+        if gdb_stub {
+            run_cmd.args(GDB_STUB_ARGS);
+        }
 
         let exit_status = run_cmd.status().unwrap();
         if !exit_status.success() {
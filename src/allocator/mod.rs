@@ -11,6 +11,7 @@ use x86_64::{
 };
 
 use crate::{memory::{lock_frame_allocator, lock_memory_mapper, print_page_table}, serial_println};
+use crate::sync::SpinLock;
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
@@ -19,19 +20,19 @@ pub const HEAP_START: usize = 0xFFFF_A000_0000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::new("heap allocator", inner),
         }
     }
 }
 
 impl<A> core::ops::Deref for Locked<A> {
-    type Target = spin::Mutex<A>;
+    type Target = SpinLock<A>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
@@ -41,8 +42,8 @@ impl<A> core::ops::Deref for Locked<A> {
 pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     let virt = VirtAddr::new(HEAP_START as u64);
     serial_println!("Heap indices: {:?}, {:?}, {:?}, {:?}", virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index());
-    let mut frame_allocator = lock_frame_allocator();
     let mut mapper = lock_memory_mapper();
+    let mut frame_allocator = lock_frame_allocator();
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
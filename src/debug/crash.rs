@@ -0,0 +1,235 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+use super::unwind::Backtrace;
+use crate::task::scheduler::{self, current_thread};
+use crate::task::thread::{Registers, ThreadId};
+use crate::{serial_print, vga};
+
+static EXCEPTION_STATE: Mutex<Option<CpuState>> = Mutex::new(None);
+
+/// Register contents at the moment something went wrong.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CpuState {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl CpuState {
+    /// Snapshots the registers at the call site.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut state = CpuState::default();
+        let base: *mut CpuState = &mut state;
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                "lea {1}, [rip]",
+                "mov [{0} + 0x80], {1}",
+                "pushfq",
+                "pop qword ptr [{0} + 0x88]",
+                "mov word ptr [{0} + 0x90], cs",
+                "mov word ptr [{0} + 0x98], ss",
+                in(reg) base,
+                out(reg) _,
+            );
+        }
+        state.read_control_registers();
+        state.cr3 = read_cr3();
+        state
+    }
+
+    /// The state an interrupt handler will return to.
+    pub fn from_interrupt(frame: &InterruptStackFrameValue, regs: &Registers) -> Self {
+        let mut state = CpuState {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rbp: regs.rbp,
+            rsp: frame.stack_pointer.as_u64(),
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: frame.instruction_pointer.as_u64(),
+            rflags: frame.cpu_flags,
+            cs: frame.code_segment,
+            ss: frame.stack_segment,
+            cr3: regs.cr3,
+            ..Default::default()
+        };
+        state.read_control_registers();
+        state
+    }
+
+    fn read_control_registers(&mut self) {
+        unsafe {
+            asm!("mov {}, cr0", out(reg) self.cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr2", out(reg) self.cr2, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) self.cr4, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+fn read_cr3() -> u64 {
+    let cr3;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    cr3
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            [("RAX", self.rax), ("RBX", self.rbx), ("RCX", self.rcx)],
+            [("RDX", self.rdx), ("RSI", self.rsi), ("RDI", self.rdi)],
+            [("RBP", self.rbp), ("RSP", self.rsp), ("R8 ", self.r8)],
+            [("R9 ", self.r9), ("R10", self.r10), ("R11", self.r11)],
+            [("R12", self.r12), ("R13", self.r13), ("R14", self.r14)],
+            [("R15", self.r15), ("RIP", self.rip), ("RFL", self.rflags)],
+            [("CS ", self.cs), ("SS ", self.ss), ("CR0", self.cr0)],
+            [("CR2", self.cr2), ("CR3", self.cr3), ("CR4", self.cr4)],
+        ];
+        for row in rows {
+            for (i, (name, value)) in row.iter().enumerate() {
+                if i != 0 {
+                    write!(f, "  ")?;
+                }
+                write!(f, "{name}={value:016x}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remembers the state of the code that raised an exception, so the crash report shows it
+/// instead of the registers of the panic handler.
+pub fn record_exception(state: CpuState) {
+    if let Some(mut exception) = EXCEPTION_STATE.try_lock() {
+        *exception = Some(state);
+    }
+}
+
+pub fn recorded_exception() -> Option<CpuState> {
+    *EXCEPTION_STATE.try_lock()?
+}
+
+/// Writes everything known about a panic to serial and replaces the screen with it.
+#[inline(always)]
+pub fn report(info: &PanicInfo) {
+    static REPORTING: AtomicBool = AtomicBool::new(false);
+    // Panicking while reporting must not recurse forever
+    if REPORTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let state = recorded_exception().unwrap_or_else(CpuState::capture);
+    let backtrace = Backtrace::from_state(&state);
+
+    let _ = write_report(&mut SerialWriter, info, &state, &backtrace);
+    if let Some(mut screen) = vga::panic_screen() {
+        let _ = write_report(&mut screen, info, &state, &backtrace);
+    }
+}
+
+fn write_report(
+    out: &mut impl Write,
+    info: &PanicInfo,
+    state: &CpuState,
+    backtrace: &Backtrace,
+) -> fmt::Result {
+    let tid = current_thread();
+    write!(out, "KERNEL PANIC in thread {} (", tid.as_u64())?;
+    write_thread_name(out, tid)?;
+    writeln!(out, ")")?;
+    writeln!(out, "{info}")?;
+    writeln!(out)?;
+    writeln!(out, "{state}")?;
+
+    writeln!(out, "Threads:")?;
+    let mut result = Ok(());
+    scheduler::for_each_thread(|tid, name, context| {
+        result = result.and_then(|()| {
+            write!(out, "  {:>3}  {:<16} ", tid.as_u64(), name)?;
+            match context {
+                Some((frame, _)) => {
+                    writeln!(out, "rip={:016x}", frame.instruction_pointer.as_u64())
+                }
+                None if tid == current_thread() => writeln!(out, "running"),
+                None => writeln!(out, "unknown"),
+            }
+        });
+    });
+    result?;
+    writeln!(out)?;
+    write!(out, "{backtrace}")
+}
+
+/// Writes the name without allocating, as the heap may be what panicked.
+fn write_thread_name(out: &mut impl Write, tid: ThreadId) -> fmt::Result {
+    let mut result = None;
+    scheduler::for_each_thread(|other, name, _| {
+        if other == tid {
+            result = Some(out.write_str(name));
+        }
+    });
+    result.unwrap_or_else(|| out.write_str("?"))
+}
+
+struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
@@ -0,0 +1,582 @@
+//! A stub speaking the GDB Remote Serial Protocol on COM2.
+//!
+//! Unlike QEMU's built-in stub, this one knows about the scheduler, so GDB sees every kernel
+//! and user thread with the registers it was paused with, and memory is read through the
+//! selected thread's page tables.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::{Mutex, Once};
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::structures::paging::{OffsetPageTable, PageTable, Translate};
+use x86_64::VirtAddr;
+
+use crate::get_physical_memory_offset;
+use crate::interrupts::dynamic;
+use crate::task::scheduler::{self, current_thread};
+use crate::task::thread::{Registers, ThreadId};
+
+const COM2: u16 = 0x2F8;
+const COM2_IRQ: u8 = 3;
+const PACKET_SIZE: usize = 0x1000;
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+static PORT: Once<Mutex<SerialPort>> = Once::new();
+static STATE: Mutex<DebugState> = Mutex::new(DebugState::new());
+
+struct DebugState {
+    /// Inserted software breakpoints and the byte each one replaced.
+    breakpoints: Vec<(u64, u8)>,
+    /// A breakpoint lifted to step over it, to be put back after the step.
+    reinsert: Option<u64>,
+    /// Whether the debugger asked for a single step, as opposed to a step over a breakpoint.
+    stepping: bool,
+    /// Thread selected with `Hg`, or the interrupted thread if `None`.
+    selected: Option<ThreadId>,
+}
+
+impl DebugState {
+    const fn new() -> Self {
+        DebugState {
+            breakpoints: Vec::new(),
+            reinsert: None,
+            stepping: false,
+            selected: None,
+        }
+    }
+}
+
+/// Sets up COM2 for the debugger and lets GDB interrupt the kernel with Ctrl-C. Returns
+/// `false` if there is no UART on COM2, in which case breakpoints keep panicking.
+pub fn init() -> bool {
+    // An absent UART reads back all ones instead of what was written to the scratch register
+    let mut scratch: Port<u8> = Port::new(COM2 + 7);
+    unsafe {
+        scratch.write(0x5A);
+        if scratch.read() != 0x5A {
+            return false;
+        }
+    }
+
+    PORT.call_once(|| {
+        let mut port = unsafe { SerialPort::new(COM2) };
+        port.init();
+        Mutex::new(port)
+    });
+    dynamic::register_closure(dynamic::irq_vector(COM2_IRQ), |stack_frame, regs| {
+        let byte = PORT.get().unwrap().lock().receive();
+        if byte == 0x03 {
+            enter(stack_frame, regs, SIGINT);
+        }
+    })
+    .expect("COM2 interrupt line is not dynamic");
+    true
+}
+
+pub fn is_enabled() -> bool {
+    PORT.get().is_some()
+}
+
+/// Handles `int3`. Returns `false` if no debugger is attached.
+pub fn handle_breakpoint(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    // Report our own breakpoints at their address rather than after the `int3`
+    let address = stack_frame.instruction_pointer.as_u64() - 1;
+    if STATE
+        .lock()
+        .breakpoints
+        .iter()
+        .any(|&(bp, _)| bp == address)
+    {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = VirtAddr::new(address));
+        }
+    }
+    enter(stack_frame, regs, SIGTRAP);
+    true
+}
+
+/// Handles the debug exception raised after a single step. Returns `false` if no debugger is
+/// attached.
+pub fn handle_debug(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    set_trap_flag(stack_frame, false);
+
+    let stepping = {
+        let mut state = STATE.lock();
+        if let Some(address) = state.reinsert.take() {
+            write_byte(kernel_cr3(), address, INT3);
+        }
+        state.stepping
+    };
+    if stepping {
+        enter(stack_frame, regs, SIGTRAP);
+    }
+    true
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+fn enter(stack_frame: &mut InterruptStackFrame, regs: &mut Registers, signal: u8) {
+    let mut state = STATE.lock();
+    let mut port = PORT.get().unwrap().lock();
+    state.selected = None;
+    state.stepping = false;
+
+    let mut reply = String::new();
+    let _ = write!(
+        reply,
+        "T{signal:02x}thread:{:x};",
+        gdb_tid(current_thread())
+    );
+    send_packet(&mut port, &reply);
+
+    let resume = loop {
+        let packet = receive_packet(&mut port);
+        reply.clear();
+        match handle_packet(&packet, &mut state, stack_frame, regs, &mut reply) {
+            Some(resume) => break resume,
+            None => send_packet(&mut port, &reply),
+        }
+    };
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let on_breakpoint = state
+        .breakpoints
+        .iter()
+        .find(|&&(bp, _)| bp == rip)
+        .copied();
+    match (resume, on_breakpoint) {
+        (Resume::Step, _) => {
+            state.stepping = true;
+            if let Some((address, original)) = on_breakpoint {
+                write_byte(kernel_cr3(), address, original);
+                state.reinsert = Some(address);
+            }
+            set_trap_flag(stack_frame, true);
+        }
+        // Execute the original instruction once before putting the breakpoint back
+        (Resume::Continue, Some((address, original))) => {
+            write_byte(kernel_cr3(), address, original);
+            state.reinsert = Some(address);
+            set_trap_flag(stack_frame, true);
+        }
+        (Resume::Continue, None) => {}
+    }
+}
+
+fn handle_packet(
+    packet: &[u8],
+    state: &mut DebugState,
+    stack_frame: &mut InterruptStackFrame,
+    regs: &mut Registers,
+    reply: &mut String,
+) -> Option<Resume> {
+    let (command, args) = match packet.split_first() {
+        Some((&command, args)) => (command, args),
+        None => return None,
+    };
+
+    match command {
+        b'?' => {
+            let _ = write!(reply, "S{SIGTRAP:02x}");
+        }
+        b'g' => match thread_registers(state.selected, stack_frame, regs) {
+            Some((frame, regs)) => write_registers(reply, &frame, &regs),
+            None => reply.push_str("E01"),
+        },
+        b'G' => {
+            if state.selected.map_or(false, |tid| tid != current_thread()) {
+                reply.push_str("E01");
+            } else {
+                read_registers(args, stack_frame, regs);
+                reply.push_str("OK");
+            }
+        }
+        b'm' => {
+            let cr3 = selected_cr3(state.selected, regs);
+            // Each byte takes two hex digits of the reply
+            let range = parse_address_length(args).and_then(|(address, length)| {
+                let length = length.min(PACKET_SIZE as u64 / 2);
+                Some(address..address.checked_add(length)?)
+            });
+            match range {
+                Some(range) => {
+                    for address in range {
+                        match read_byte(cr3, address) {
+                            Some(byte) => {
+                                let _ = write!(reply, "{byte:02x}");
+                            }
+                            None => break,
+                        }
+                    }
+                    if reply.is_empty() {
+                        reply.push_str("E14");
+                    }
+                }
+                None => reply.push_str("E01"),
+            }
+        }
+        b'M' => {
+            let cr3 = selected_cr3(state.selected, regs);
+            let mut parts = args.splitn(2, |&b| b == b':');
+            let result = parts
+                .next()
+                .and_then(parse_address_length)
+                .zip(parts.next())
+                .and_then(|((address, length), data)| {
+                    (0..length).try_for_each(|i| {
+                        let i = i as usize;
+                        let byte = parse_hex(data.get(2 * i..2 * i + 2)?)? as u8;
+                        write_byte(cr3, address.checked_add(i as u64)?, byte)
+                    })
+                });
+            reply.push_str(if result.is_some() { "OK" } else { "E14" });
+        }
+        b'Z' | b'z' if args.first() == Some(&b'0') => {
+            let address = args
+                .split(|&b| b == b',')
+                .nth(1)
+                .and_then(parse_hex)
+                .unwrap_or(0);
+            let ok = if command == b'Z' {
+                insert_breakpoint(state, address)
+            } else {
+                remove_breakpoint(state, address)
+            };
+            reply.push_str(if ok { "OK" } else { "E01" });
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(args) {
+                unsafe {
+                    stack_frame
+                        .as_mut()
+                        .update(|frame| frame.instruction_pointer = VirtAddr::new(address));
+                }
+            }
+            return Some(if command == b's' {
+                Resume::Step
+            } else {
+                Resume::Continue
+            });
+        }
+        b'D' => {
+            while let Some(&(address, _)) = state.breakpoints.first() {
+                remove_breakpoint(state, address);
+            }
+            return Some(Resume::Continue);
+        }
+        b'k' => return Some(Resume::Continue),
+        b'H' if args.first() == Some(&b'g') => {
+            state.selected = parse_tid(&args[1..]);
+            reply.push_str("OK");
+        }
+        b'H' => reply.push_str("OK"),
+        b'T' => match parse_tid(args) {
+            Some(tid) if thread_exists(tid) => reply.push_str("OK"),
+            _ => reply.push_str("E01"),
+        },
+        b'q' => handle_query(args, reply),
+        _ => {}
+    }
+    None
+}
+
+fn handle_query(query: &[u8], reply: &mut String) {
+    if query.starts_with(b"Supported") {
+        let _ = write!(reply, "PacketSize={PACKET_SIZE:x}");
+    } else if query == b"Attached" {
+        reply.push_str("1");
+    } else if query == b"C" {
+        let _ = write!(reply, "QC{:x}", gdb_tid(current_thread()));
+    } else if query == b"fThreadInfo" {
+        reply.push('m');
+        for (i, tid) in scheduler::thread_ids().into_iter().enumerate() {
+            if i != 0 {
+                reply.push(',');
+            }
+            let _ = write!(reply, "{:x}", gdb_tid(tid));
+        }
+    } else if query == b"sThreadInfo" {
+        reply.push('l');
+    } else if let Some(tid) = query.strip_prefix(b"ThreadExtraInfo,") {
+        let tid = parse_tid(tid);
+        let mode = match tid.and_then(scheduler::thread_context) {
+            Some((frame, _)) if frame.code_segment & 3 == 3 => "user",
+            Some(_) => "kernel",
+            None if tid == Some(current_thread()) => "running",
+            None => "unknown",
+        };
+        let name = tid.and_then(scheduler::thread_info).map(|info| info.name);
+        let info = format!("{}, {mode}", name.as_deref().unwrap_or("?"));
+        for byte in info.bytes() {
+            let _ = write!(reply, "{byte:02x}");
+        }
+    }
+}
+
+/// GDB treats thread id 0 as "any thread", so ours are shifted by one.
+fn gdb_tid(tid: ThreadId) -> u64 {
+    tid.as_u64() + 1
+}
+
+fn parse_tid(data: &[u8]) -> Option<ThreadId> {
+    match parse_hex(data)? {
+        0 => None,
+        tid => Some(unsafe { ThreadId::from_u64(tid - 1) }),
+    }
+}
+
+fn thread_exists(tid: ThreadId) -> bool {
+    scheduler::thread_ids().contains(&tid)
+}
+
+fn thread_registers(
+    selected: Option<ThreadId>,
+    stack_frame: &InterruptStackFrame,
+    regs: &Registers,
+) -> Option<(InterruptStackFrameValue, Registers)> {
+    match selected {
+        Some(tid) if tid != current_thread() => scheduler::thread_context(tid),
+        _ => Some((**stack_frame, *regs)),
+    }
+}
+
+fn selected_cr3(selected: Option<ThreadId>, regs: &Registers) -> u64 {
+    match selected {
+        Some(tid) if tid != current_thread() => {
+            scheduler::thread_context(tid).map_or_else(kernel_cr3, |(_, regs)| regs.cr3)
+        }
+        _ => regs.cr3,
+    }
+}
+
+fn kernel_cr3() -> u64 {
+    crate::get_kernel_cr3().start_address().as_u64()
+}
+
+/// The registers in the order of GDB's x86-64 target description, up to `gs`.
+fn write_registers(reply: &mut String, frame: &InterruptStackFrameValue, regs: &Registers) {
+    let general = [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        frame.stack_pointer.as_u64(),
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        frame.instruction_pointer.as_u64(),
+    ];
+    for value in general {
+        write_hex_le(reply, value, 8);
+    }
+    let segments = [
+        frame.cpu_flags,
+        frame.code_segment,
+        frame.stack_segment,
+        0,
+        0,
+        0,
+        0,
+    ];
+    for value in segments {
+        write_hex_le(reply, value, 4);
+    }
+}
+
+fn read_registers(data: &[u8], stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    let mut values = data.chunks(16).map(parse_hex_le);
+    let mut next = || values.next().flatten();
+    let general: [Option<u64>; 17] = [(); 17].map(|_| next());
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+        general;
+
+    let targets = [
+        (&mut regs.rax, rax),
+        (&mut regs.rbx, rbx),
+        (&mut regs.rcx, rcx),
+        (&mut regs.rdx, rdx),
+        (&mut regs.rsi, rsi),
+        (&mut regs.rdi, rdi),
+        (&mut regs.rbp, rbp),
+        (&mut regs.r8, r8),
+        (&mut regs.r9, r9),
+        (&mut regs.r10, r10),
+        (&mut regs.r11, r11),
+        (&mut regs.r12, r12),
+        (&mut regs.r13, r13),
+        (&mut regs.r14, r14),
+        (&mut regs.r15, r15),
+    ];
+    for (target, value) in targets {
+        if let Some(value) = value {
+            *target = value;
+        }
+    }
+    let rflags = data.get(17 * 16..17 * 16 + 8).and_then(parse_hex_le);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            if let Some(rsp) = rsp {
+                frame.stack_pointer = VirtAddr::new(rsp);
+            }
+            if let Some(rip) = rip {
+                frame.instruction_pointer = VirtAddr::new(rip);
+            }
+            if let Some(rflags) = rflags {
+                frame.cpu_flags = rflags;
+            }
+        });
+    }
+}
+
+fn set_trap_flag(stack_frame: &mut InterruptStackFrame, enabled: bool) {
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            if enabled {
+                frame.cpu_flags |= TRAP_FLAG;
+            } else {
+                frame.cpu_flags &= !TRAP_FLAG;
+            }
+        });
+    }
+}
+
+fn insert_breakpoint(state: &mut DebugState, address: u64) -> bool {
+    if state.breakpoints.iter().any(|&(bp, _)| bp == address) {
+        return true;
+    }
+    match read_byte(kernel_cr3(), address) {
+        Some(original) => {
+            write_byte(kernel_cr3(), address, INT3);
+            state.breakpoints.push((address, original));
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove_breakpoint(state: &mut DebugState, address: u64) -> bool {
+    match state.breakpoints.iter().position(|&(bp, _)| bp == address) {
+        Some(index) => {
+            let (_, original) = state.breakpoints.remove(index);
+            if state.reinsert == Some(address) {
+                state.reinsert = None;
+            }
+            write_byte(kernel_cr3(), address, original);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Memory is accessed through the physical memory mapping, so this works for any address
+/// space and also for read-only kernel code.
+fn physical_pointer(cr3: u64, address: u64) -> Option<*mut u8> {
+    let offset = get_physical_memory_offset();
+    let l4 = unsafe { &mut *(((cr3 & !0xFFF) + offset) as *mut PageTable) };
+    let mapper = unsafe { OffsetPageTable::new(l4, VirtAddr::new(offset)) };
+    let physical = mapper.translate_addr(VirtAddr::try_new(address).ok()?)?;
+    Some((physical.as_u64() + offset) as *mut u8)
+}
+
+fn read_byte(cr3: u64, address: u64) -> Option<u8> {
+    physical_pointer(cr3, address).map(|ptr| unsafe { ptr.read_volatile() })
+}
+
+fn write_byte(cr3: u64, address: u64, byte: u8) -> Option<()> {
+    physical_pointer(cr3, address).map(|ptr| unsafe { ptr.write_volatile(byte) })
+}
+
+fn receive_packet(port: &mut SerialPort) -> Vec<u8> {
+    loop {
+        // Skip acks and Ctrl-C until the start of a packet
+        while port.receive() != b'$' {}
+
+        let mut packet = Vec::new();
+        let mut checksum: u8 = 0;
+        loop {
+            match port.receive() {
+                b'#' => break,
+                byte => {
+                    checksum = checksum.wrapping_add(byte);
+                    packet.push(byte);
+                }
+            }
+        }
+        let expected = [port.receive(), port.receive()];
+        if parse_hex(&expected) == Some(checksum as u64) {
+            port.send(b'+');
+            return packet;
+        }
+        port.send(b'-');
+    }
+}
+
+fn send_packet(port: &mut SerialPort, data: &str) {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    loop {
+        port.send(b'$');
+        data.bytes().for_each(|byte| port.send(byte));
+        let _ = write!(port, "#{checksum:02x}");
+        if port.receive() == b'+' {
+            return;
+        }
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Option<u64> {
+    if data.is_empty() {
+        return None;
+    }
+    data.iter().try_fold(0u64, |value, &digit| {
+        let digit = (digit as char).to_digit(16)? as u64;
+        value.checked_mul(16).map(|value| value + digit)
+    })
+}
+
+/// Parses a register in target byte order.
+fn parse_hex_le(data: &[u8]) -> Option<u64> {
+    data.chunks(2)
+        .enumerate()
+        .try_fold(0u64, |value, (i, byte)| {
+            Some(value | parse_hex(byte)? << (8 * i))
+        })
+}
+
+fn write_hex_le(reply: &mut String, value: u64, bytes: usize) {
+    for byte in value.to_le_bytes().iter().take(bytes) {
+        let _ = write!(reply, "{byte:02x}");
+    }
+}
+
+fn parse_address_length(data: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = data.split(|&b| b == b',');
+    let address = parse_hex(parts.next()?)?;
+    let length = parse_hex(parts.next()?)?;
+    Some((address, length))
+}
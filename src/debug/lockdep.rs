@@ -0,0 +1,325 @@
+//! Checks how [`SpinLock`](crate::sync::SpinLock)s are used, in debug builds.
+//!
+//! Locks are grouped into classes by name. The validator remembers which classes have been
+//! taken while another one was held, and warns on serial as soon as two classes are taken in
+//! both orders, since two threads doing that at the same time deadlock. It also warns about
+//! classes taken in interrupt handlers that are held elsewhere with interrupts enabled, and
+//! panics instead of spinning forever when a lock is taken again by whoever holds it.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::serial_println;
+
+/// How many locks a thread can hold at once before the validator stops keeping track.
+const MAX_HELD: usize = 16;
+/// Classes beyond this are not checked.
+const MAX_CLASSES: usize = 64;
+/// The class of a lock that has not been taken yet.
+pub(crate) const UNREGISTERED: usize = usize::MAX;
+/// The class of a lock that could not get one because all are in use.
+const UNTRACKED: usize = usize::MAX - 1;
+
+static VALIDATOR: Mutex<Validator> = Mutex::new(Validator::new());
+
+/// The locks a thread holds. The scheduler swaps them in and out along with the thread, as a
+/// thread can be preempted while holding a lock and another thread take it next.
+#[derive(Debug, Clone, Copy)]
+pub struct HeldLocks {
+    locks: [HeldLock; MAX_HELD],
+    count: usize,
+    /// How many interrupt handlers the thread is nested in.
+    interrupt_depth: usize,
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HeldLock {
+    address: usize,
+    class: usize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        HeldLocks {
+            locks: [HeldLock {
+                address: 0,
+                class: UNTRACKED,
+            }; MAX_HELD],
+            count: 0,
+            interrupt_depth: 0,
+        }
+    }
+
+    fn iter(&self) -> core::slice::Iter<HeldLock> {
+        self.locks[..self.count.min(MAX_HELD)].iter()
+    }
+
+    fn push(&mut self, lock: HeldLock) {
+        if self.count < MAX_HELD {
+            self.locks[self.count] = lock;
+        }
+        self.count += 1;
+    }
+
+    fn remove(&mut self, address: usize) {
+        if self.count > MAX_HELD {
+            // The overflowing locks are not tracked, so any of them may be the one released
+            self.count -= 1;
+            return;
+        }
+        // Guards are usually dropped in reverse order, but not always
+        if let Some(index) = self.iter().rposition(|held| held.address == address) {
+            self.locks.copy_within(index + 1..self.count, index);
+            self.count -= 1;
+        }
+    }
+}
+
+struct Validator {
+    names: [&'static str; MAX_CLASSES],
+    classes: usize,
+    /// Bit `b` of `taken_after[a]` is set once class `b` was taken while `a` was held.
+    taken_after: [u64; MAX_CLASSES],
+    /// Class pairs whose inversion was reported already, in the same layout.
+    reported_inversions: [u64; MAX_CLASSES],
+    taken_in_interrupt: u64,
+    taken_with_interrupts_enabled: u64,
+    reported_interrupt_unsafe: u64,
+    /// The locks of the running thread.
+    held: HeldLocks,
+}
+
+enum Verdict {
+    Ok,
+    /// The lock is held by the thread taking it again.
+    Deadlock,
+}
+
+impl Validator {
+    const fn new() -> Self {
+        Validator {
+            names: [""; MAX_CLASSES],
+            classes: 0,
+            taken_after: [0; MAX_CLASSES],
+            reported_inversions: [0; MAX_CLASSES],
+            taken_in_interrupt: 0,
+            taken_with_interrupts_enabled: 0,
+            reported_interrupt_unsafe: 0,
+            held: HeldLocks::new(),
+        }
+    }
+
+    fn class(&mut self, name: &'static str) -> usize {
+        if let Some(class) = self.names[..self.classes].iter().position(|&n| n == name) {
+            return class;
+        }
+        if self.classes == MAX_CLASSES {
+            serial_println!("lockdep: too many lock classes, not checking {name}");
+            return UNTRACKED;
+        }
+        self.names[self.classes] = name;
+        self.classes += 1;
+        self.classes - 1
+    }
+
+    fn acquire(&mut self, address: usize, class: usize, interrupts_enabled: bool) -> Verdict {
+        if self.held.iter().any(|held| held.address == address) {
+            return Verdict::Deadlock;
+        }
+        if class != UNTRACKED {
+            self.check_order(class);
+            self.check_interrupt_safety(class, interrupts_enabled);
+        }
+        self.held.push(HeldLock { address, class });
+        Verdict::Ok
+    }
+
+    fn check_order(&mut self, class: usize) {
+        let bit = 1 << class;
+        for index in 0..self.held.count.min(MAX_HELD) {
+            let held = self.held.locks[index].class;
+            if held == UNTRACKED || held == class {
+                continue;
+            }
+            let held_bit = 1 << held;
+            let inverted = self.taken_after[class] & held_bit != 0;
+            if inverted && self.reported_inversions[held] & bit == 0 {
+                self.reported_inversions[held] |= bit;
+                self.reported_inversions[class] |= held_bit;
+                serial_println!(
+                    "lockdep: lock order inversion: taking {} while holding {}, but {} has been \
+                     taken while holding {} before",
+                    self.names[class],
+                    self.names[held],
+                    self.names[held],
+                    self.names[class],
+                );
+            }
+            self.taken_after[held] |= bit;
+        }
+    }
+
+    fn check_interrupt_safety(&mut self, class: usize, interrupts_enabled: bool) {
+        let bit = 1 << class;
+        if self.held.interrupt_depth > 0 {
+            self.taken_in_interrupt |= bit;
+        } else if interrupts_enabled {
+            self.taken_with_interrupts_enabled |= bit;
+        }
+        let unsafe_classes = self.taken_in_interrupt & self.taken_with_interrupts_enabled;
+        if unsafe_classes & bit != 0 && self.reported_interrupt_unsafe & bit == 0 {
+            self.reported_interrupt_unsafe |= bit;
+            serial_println!(
+                "lockdep: {} is taken in interrupt handlers, but also held with interrupts \
+                 enabled, so an interrupt arriving meanwhile spins on it forever",
+                self.names[class],
+            );
+        }
+    }
+}
+
+/// Called right before spinning on the lock at `address`. Registers the lock's class on first
+/// use.
+pub(crate) fn before_lock(address: usize, class: &AtomicUsize, name: &'static str) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let interrupts_enabled = interrupts::are_enabled();
+    let verdict = interrupts::without_interrupts(|| {
+        let mut validator = VALIDATOR.lock();
+        let mut id = class.load(Ordering::Relaxed);
+        if id == UNREGISTERED {
+            id = validator.class(name);
+            class.store(id, Ordering::Relaxed);
+        }
+        let in_interrupt = validator.held.interrupt_depth > 0;
+        (
+            validator.acquire(address, id, interrupts_enabled),
+            in_interrupt,
+        )
+    });
+    match verdict {
+        (Verdict::Ok, _) => {}
+        (Verdict::Deadlock, true) => {
+            panic!("Deadlock: interrupt handler takes {name}, which the code it interrupted holds")
+        }
+        (Verdict::Deadlock, false) => panic!("Deadlock: {name} is taken again while already held"),
+    }
+}
+
+/// Records a lock taken without spinning, which cannot deadlock.
+pub(crate) fn after_try_lock(address: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        VALIDATOR.lock().held.push(HeldLock {
+            address,
+            class: UNTRACKED,
+        })
+    });
+}
+
+pub(crate) fn unlocked(address: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    interrupts::without_interrupts(|| VALIDATOR.lock().held.remove(address));
+}
+
+/// Called when a hardware interrupt handler starts running.
+pub(crate) fn enter_interrupt() {
+    if cfg!(debug_assertions) {
+        VALIDATOR.lock().held.interrupt_depth += 1;
+    }
+}
+
+pub(crate) fn exit_interrupt() {
+    if cfg!(debug_assertions) {
+        VALIDATOR.lock().held.interrupt_depth -= 1;
+    }
+}
+
+/// Saves the running thread's locks to `previous` and makes `next` the running ones.
+///
+/// Interrupts must be disabled.
+pub(crate) unsafe fn switch_threads(previous: *mut HeldLocks, next: *const HeldLocks) {
+    if cfg!(debug_assertions) {
+        let mut validator = VALIDATOR.lock();
+        *previous = validator.held;
+        validator.held = *next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn classes_are_shared_by_name() {
+        let mut validator = Validator::new();
+        let a = validator.class("a");
+        let b = validator.class("b");
+        assert_ne!(a, b);
+        assert_eq!(validator.class("a"), a);
+    }
+
+    #[test_case]
+    fn records_lock_order() {
+        let mut validator = Validator::new();
+        let (a, b) = (validator.class("a"), validator.class("b"));
+        assert!(matches!(validator.acquire(1, a, false), Verdict::Ok));
+        assert!(matches!(validator.acquire(2, b, false), Verdict::Ok));
+        assert_eq!(validator.taken_after[a], 1 << b);
+        assert_eq!(validator.taken_after[b], 0);
+        validator.held.remove(2);
+        validator.held.remove(1);
+
+        // The other order is reported once
+        validator.acquire(2, b, false);
+        validator.acquire(1, a, false);
+        assert_eq!(validator.reported_inversions[b], 1 << a);
+        assert_eq!(validator.held.count, 2);
+    }
+
+    #[test_case]
+    fn detects_taking_a_held_lock() {
+        let mut validator = Validator::new();
+        let a = validator.class("a");
+        assert!(matches!(validator.acquire(1, a, false), Verdict::Ok));
+        assert!(matches!(validator.acquire(1, a, false), Verdict::Deadlock));
+        // Another lock of the same class is fine
+        assert!(matches!(validator.acquire(2, a, false), Verdict::Ok));
+    }
+
+    #[test_case]
+    fn detects_interrupt_unsafe_locks() {
+        let mut validator = Validator::new();
+        let a = validator.class("a");
+        validator.acquire(1, a, true);
+        validator.held.remove(1);
+        assert_eq!(validator.reported_interrupt_unsafe, 0);
+
+        validator.held.interrupt_depth = 1;
+        validator.acquire(1, a, false);
+        assert_eq!(validator.reported_interrupt_unsafe, 1 << a);
+    }
+
+    #[test_case]
+    fn releases_out_of_order() {
+        let mut held = HeldLocks::new();
+        for address in 1..=3 {
+            held.push(HeldLock { address, class: 0 });
+        }
+        held.remove(2);
+        let addresses: alloc::vec::Vec<usize> = held.iter().map(|lock| lock.address).collect();
+        assert_eq!(addresses, [1, 3]);
+    }
+}
@@ -0,0 +1,55 @@
+pub mod crash;
+pub mod gdbstub;
+pub mod lockdep;
+pub mod symbols;
+pub mod unwind;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::serial_println;
+use unwind::Backtrace;
+
+/// Prints a symbolized backtrace of the panicking code to serial. Traces from the faulting
+/// instruction when the panic comes from an exception handler.
+#[inline(always)]
+pub fn print_panic_backtrace() {
+    static PRINTING: AtomicBool = AtomicBool::new(false);
+    // A fault while walking or symbolizing must not recurse forever
+    if PRINTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let backtrace = Backtrace::from_recorded_exception().unwrap_or_else(Backtrace::capture);
+    serial_println!("{}", backtrace);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crash::CpuState;
+    use super::symbols;
+    use super::unwind::Backtrace;
+    use alloc::format;
+
+    #[test_case]
+    fn capture_backtrace() {
+        let backtrace = Backtrace::capture();
+        assert!(!backtrace.frames().is_empty());
+    }
+
+    #[test_case]
+    fn capture_cpu_state() {
+        use x86_64::registers::control::Cr3;
+
+        let state = CpuState::capture();
+        let (frame, _) = Cr3::read();
+        assert_eq!(state.cr3 & !0xFFF, frame.start_address().as_u64());
+        assert!(format!("{state}").contains(&format!("CR3={:016x}", state.cr3)));
+    }
+
+    #[test_case]
+    fn resolve_own_symbol() {
+        let address = symbols::resolve as usize as u64;
+        let symbol = symbols::resolve(address).expect("Kernel symbols not found");
+        assert_eq!(symbol.offset, 0);
+        assert!(format!("{symbol}").contains("symbols::resolve"));
+    }
+}
@@ -0,0 +1,188 @@
+use bootloader::boot_info::MemoryRegionKind;
+use core::fmt;
+use core::slice;
+use gimli::{EndianSlice, LittleEndian};
+use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol, SymbolKind};
+use spin::Once;
+
+use crate::{get_memory_regions, get_physical_memory_offset};
+
+type Reader = EndianSlice<'static, LittleEndian>;
+
+static KERNEL_ELF: Once<Option<&'static [u8]>> = Once::new();
+
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+    pub location: Option<Location>,
+}
+
+pub struct Location {
+    pub directory: Option<&'static str>,
+    pub file: &'static str,
+    pub line: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#}+{:#x}",
+            rustc_demangle::demangle(self.name),
+            self.offset
+        )?;
+        if let Some(location) = &self.location {
+            write!(f, " ({location})")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.directory {
+            Some(directory) if !self.file.starts_with('/') => {
+                write!(f, "{}/{}:{}", directory, self.file, self.line)
+            }
+            _ => write!(f, "{}:{}", self.file, self.line),
+        }
+    }
+}
+
+/// Looks up the function containing `address` in the kernel's symbol table, and the source
+/// line in its DWARF line programs.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    let elf = object::File::parse(kernel_elf()?).ok()?;
+
+    let mut best: Option<(u64, &'static str)> = None;
+    for symbol in elf.symbols() {
+        if symbol.kind() != SymbolKind::Text || symbol.address() > address {
+            continue;
+        }
+        if symbol.size() != 0 && address >= symbol.address() + symbol.size() {
+            continue;
+        }
+        if best.map_or(true, |(start, _)| symbol.address() > start) {
+            if let Ok(name) = symbol.name() {
+                best = Some((symbol.address(), name));
+            }
+        }
+    }
+    let (start, name) = best?;
+
+    Some(Symbol {
+        name,
+        offset: address - start,
+        location: find_location(&elf, address),
+    })
+}
+
+fn find_location(elf: &object::File<'static>, address: u64) -> Option<Location> {
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, ()> {
+        let data = elf
+            .section_by_name(id.name())
+            .and_then(|section| section.data().ok())
+            .unwrap_or(&[]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    })
+    .ok()?;
+
+    let mut units = dwarf.units();
+    while let Ok(Some(header)) = units.next() {
+        let unit = match dwarf.unit(header) {
+            Ok(unit) => unit,
+            Err(_) => continue,
+        };
+        let mut ranges = match dwarf.unit_ranges(&unit) {
+            Ok(ranges) => ranges,
+            Err(_) => continue,
+        };
+        let mut in_unit = false;
+        while let Ok(Some(range)) = ranges.next() {
+            if range.begin <= address && address < range.end {
+                in_unit = true;
+                break;
+            }
+        }
+        if !in_unit {
+            continue;
+        }
+
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+        let mut rows = program.rows();
+        let mut previous: Option<(u64, u64, u64)> = None;
+        while let Ok(Some((header, row))) = rows.next_row() {
+            if let Some((start, file, line)) = previous {
+                if start <= address && address < row.address() {
+                    let entry = header.file(file)?;
+                    let file = dwarf.attr_string(&unit, entry.path_name()).ok()?;
+                    let directory = entry
+                        .directory(header)
+                        .and_then(|directory| dwarf.attr_string(&unit, directory).ok())
+                        .and_then(|directory| directory.to_string().ok());
+                    return Some(Location {
+                        directory,
+                        file: file.to_string().ok()?,
+                        line,
+                    });
+                }
+            }
+            previous = if row.end_sequence() {
+                None
+            } else {
+                let line = row.line().map_or(0, |line| line.get());
+                Some((row.address(), row.file_index(), line))
+            };
+        }
+    }
+    None
+}
+
+fn kernel_elf() -> Option<&'static [u8]> {
+    *KERNEL_ELF.call_once(find_kernel_elf)
+}
+
+/// The bootloader keeps the kernel ELF file it loaded us from in memory it marks as its own.
+/// It does not tell us where, so look for an ELF image whose code matches ours.
+fn find_kernel_elf() -> Option<&'static [u8]> {
+    let physical_memory_offset = get_physical_memory_offset();
+
+    get_memory_regions()
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Bootloader)
+        .find_map(|region| {
+            (region.start..region.end).step_by(16).find_map(|start| {
+                let data = unsafe {
+                    slice::from_raw_parts(
+                        (start + physical_memory_offset) as *const u8,
+                        (region.end - start) as usize,
+                    )
+                };
+                as_kernel_elf(data)
+            })
+        })
+}
+
+fn as_kernel_elf(data: &'static [u8]) -> Option<&'static [u8]> {
+    if data.len() < 64 || &data[..4] != b"\x7fELF" {
+        return None;
+    }
+    // The section header table is the last thing lld writes, so it tells us the file size
+    let section_headers = u64::from_le_bytes(data[0x28..0x30].try_into().ok()?) as usize;
+    let entry_size = u16::from_le_bytes(data[0x3A..0x3C].try_into().ok()?) as usize;
+    let entries = u16::from_le_bytes(data[0x3C..0x3E].try_into().ok()?) as usize;
+    let data = data.get(..section_headers + entry_size * entries)?;
+
+    let elf = object::File::parse(data).ok()?;
+    let probe = as_kernel_elf as usize as u64;
+    let segment = elf
+        .segments()
+        .find(|segment| segment.address() <= probe && probe < segment.address() + segment.size())?;
+    let offset = (probe - segment.address()) as usize;
+    let expected = segment.data().ok()?.get(offset..offset + 32)?;
+    let actual = unsafe { slice::from_raw_parts(probe as *const u8, 32) };
+    (expected == actual).then(|| data)
+}
@@ -0,0 +1,135 @@
+use core::arch::asm;
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+use super::crash::{self, CpuState};
+use super::symbols;
+use crate::memory;
+use crate::task::thread::Registers;
+
+const MAX_FRAMES: usize = 32;
+
+/// Remembers where an exception happened so that the panic it causes can be traced from the
+/// faulting instruction instead of from inside the handler.
+///
+/// Must be called directly from the `x86-interrupt` handler, whose frame pointer still links to
+/// the interrupted frame.
+#[inline(always)]
+pub fn record_exception(stack_frame: &InterruptStackFrame) {
+    // Only the registers the handler prologue has not touched yet are exact
+    let mut state = CpuState::capture();
+    state.rbp = read_stack(state.rbp).unwrap_or(0);
+    state.rip = stack_frame.instruction_pointer.as_u64();
+    state.rsp = stack_frame.stack_pointer.as_u64();
+    state.rflags = stack_frame.cpu_flags;
+    state.cs = stack_frame.code_segment;
+    state.ss = stack_frame.stack_segment;
+    crash::record_exception(state);
+}
+
+/// Like [`record_exception`], for handlers that have the interrupted registers at hand.
+pub fn record_interrupt(stack_frame: &InterruptStackFrame, regs: &Registers) {
+    crash::record_exception(CpuState::from_interrupt(stack_frame, regs));
+}
+
+/// Return addresses collected by following the frame pointer chain. The kernel is built with
+/// `-C force-frame-pointers=yes` so every function keeps `rbp` pointing at its saved caller `rbp`.
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    /// The first frame is the exact faulting instruction rather than a return address.
+    exact_first: bool,
+}
+
+impl Backtrace {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        Self::walk(None, rbp)
+    }
+
+    /// Traces the last exception passed to [`record_exception`], if any.
+    pub fn from_recorded_exception() -> Option<Self> {
+        crash::recorded_exception().map(|state| Self::from_state(&state))
+    }
+
+    pub fn from_state(state: &CpuState) -> Self {
+        Self::walk(Some(state.rip), state.rbp)
+    }
+
+    pub fn from_registers(rip: u64, rbp: u64) -> Self {
+        Self::walk(Some(rip), rbp)
+    }
+
+    fn walk(rip: Option<u64>, mut rbp: u64) -> Self {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            exact_first: rip.is_some(),
+        };
+        if let Some(rip) = rip {
+            backtrace.push(rip);
+        }
+
+        while backtrace.len < MAX_FRAMES {
+            let (caller_rbp, return_address) = match (read_stack(rbp), read_stack(rbp + 8)) {
+                (Some(caller_rbp), Some(return_address)) => (caller_rbp, return_address),
+                _ => break,
+            };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.push(return_address);
+            // Stacks grow down, so anything else means the chain is corrupt
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+        backtrace
+    }
+
+    fn push(&mut self, address: u64) {
+        self.frames[self.len] = address;
+        self.len += 1;
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            // Return addresses point after the call, which may already be the next line
+            let lookup = if i == 0 && self.exact_first {
+                address
+            } else {
+                address - 1
+            };
+            write!(f, "  {i:>2}: {address:#018x}")?;
+            match symbols::resolve(lookup) {
+                Some(symbol) => writeln!(f, " - {symbol}")?,
+                None => writeln!(f, " - <unknown>")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a word of the stack, refusing addresses that are not mapped so a corrupt frame
+/// pointer cannot fault while we are already handling a panic.
+fn read_stack(address: u64) -> Option<u64> {
+    if address == 0 || address % 8 != 0 {
+        return None;
+    }
+    let address = VirtAddr::try_new(address).ok()?;
+    let mapper = unsafe { memory::init() };
+    mapper.translate_addr(address)?;
+    Some(unsafe { address.as_ptr::<u64>().read() })
+}
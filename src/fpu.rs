@@ -0,0 +1,163 @@
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::task::scheduler::{self, current_thread};
+use crate::task::thread::ThreadId;
+
+const FXSAVE_AREA_SIZE: usize = 512;
+/// The x87 control word after `fninit`: all exceptions masked, 64-bit precision.
+const DEFAULT_FCW: u16 = 0x37F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+/// Offset of MXCSR in both the FXSAVE and the XSAVE layout.
+const MXCSR_OFFSET: usize = 24;
+const NO_OWNER: u64 = u64::MAX;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+/// The thread whose state is currently loaded in the FPU/SIMD registers.
+static OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+
+/// Enables the FPU, SSE and, if available, AVX, and arms lazy state switching: with `CR0.TS`
+/// set, the first FPU/SIMD instruction a thread executes traps into `device_not_available`.
+pub fn init() {
+    let features = unsafe { __cpuid(1) };
+    let has_xsave = features.ecx & (1 << 26) != 0;
+    let has_avx = features.ecx & (1 << 28) != 0;
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if has_xsave {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+    }
+
+    if has_xsave {
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_avx {
+            components |= XCr0Flags::AVX;
+        }
+        unsafe { XCr0::write(components) };
+        // EBX reports the area size needed for the components currently enabled in XCR0
+        let size = unsafe { __cpuid_count(0xD, 0) }.ebx as usize;
+        AREA_SIZE.store(size.max(FXSAVE_AREA_SIZE), Ordering::SeqCst);
+        XSAVE_MASK.store(components.bits(), Ordering::SeqCst);
+        USE_XSAVE.store(true, Ordering::SeqCst);
+    }
+
+    set_task_switched(true);
+}
+
+/// Called whenever `next` is about to run. The FPU is only handed over when `next` actually
+/// touches it, so threads that never use floating point pay nothing.
+pub fn switch_to(next: ThreadId) {
+    set_task_switched(OWNER.load(Ordering::SeqCst) != next.as_u64());
+}
+
+/// Forgets the owner of the FPU registers if it is `tid`, e.g. because the thread is gone.
+pub fn release(tid: ThreadId) {
+    let _ = OWNER.compare_exchange(tid.as_u64(), NO_OWNER, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+pub fn handle_device_not_available() {
+    set_task_switched(false);
+
+    let current = current_thread();
+    let previous = OWNER.swap(current.as_u64(), Ordering::SeqCst);
+    if previous == current.as_u64() {
+        return;
+    }
+    let previous = (previous != NO_OWNER).then(|| unsafe { ThreadId::from_u64(previous) });
+    scheduler::swap_fpu_state(previous, current);
+}
+
+fn set_task_switched(switched: bool) {
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, switched));
+    }
+}
+
+/// Saved FPU/SSE/AVX registers of a thread, in FXSAVE or XSAVE format.
+#[derive(Debug)]
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// A clean state, as after `fninit`, with all SSE/AVX registers zero. For XSAVE, the zeroed
+    /// header marks every component as being in its initial state.
+    pub fn new() -> Self {
+        let area = unsafe { alloc_zeroed(Self::layout()) };
+        let area = NonNull::new(area).expect("Failed to allocate FPU state");
+        unsafe {
+            area.as_ptr().cast::<u16>().write(DEFAULT_FCW);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+        FpuState { area }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(AREA_SIZE.load(Ordering::SeqCst), 64).unwrap()
+    }
+
+    /// Stores the current FPU registers into this area.
+    ///
+    /// `CR0.TS` must be clear.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            let mask = XSAVE_MASK.load(Ordering::SeqCst);
+            asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(nostack),
+            );
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+
+    /// Loads this area into the FPU registers. A state that was never saved clears every
+    /// register, so nothing of the previous owner is left behind.
+    ///
+    /// `CR0.TS` must be clear.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            let mask = XSAVE_MASK.load(Ordering::SeqCst);
+            asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(nostack, readonly),
+            );
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+        }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), Self::layout()) }
+    }
+}
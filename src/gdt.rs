@@ -1,18 +1,18 @@
 use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{Segment, CS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::sync::SpinLock;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub const TIMER_IST_INDEX: u16 = 1;
-pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
 lazy_static! {
-    static ref TSS: Mutex<UnsafeCell<TaskStateSegment>> = {
+    static ref TSS: SpinLock<UnsafeCell<TaskStateSegment>> = {
         let mut tss = TaskStateSegment::new();
         tss.privilege_stack_table[0/*ring 0*/] = {
             const STACK_SIZE: usize = 4096 * 5;
@@ -30,14 +30,6 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss.interrupt_stack_table[TIMER_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
         tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
@@ -46,7 +38,7 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        Mutex::new(UnsafeCell::new(tss))
+        SpinLock::new("tss", UnsafeCell::new(tss))
     };
     pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
@@ -92,3 +84,9 @@ pub fn init() {
         load_tss(*current_tss);
     }
 }
+
+/// Sets the stack the CPU switches to when an interrupt arrives while in user mode.
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    let tss = TSS.lock();
+    unsafe { (*tss.get()).privilege_stack_table[0] = stack_end };
+}
@@ -0,0 +1,217 @@
+use super::{InterruptIndex, PICS, PIC_1_OFFSET, PIC_2_OFFSET};
+use crate::task::thread::Registers;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Lowest vector that can have handlers registered at runtime. The timer and keyboard lines
+/// below it are wired up statically.
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_1_OFFSET + 2;
+/// One past the highest vector that can have handlers registered at runtime.
+pub const END_DYNAMIC_VECTOR: u8 = InterruptIndex::Syscall as u8;
+/// Lowest vector handed out by [`allocate_vector`]; everything below belongs to the PICs.
+pub const FIRST_FREE_VECTOR: u8 = PIC_2_OFFSET + 8;
+
+pub type HandlerFn =
+    fn(context: *mut (), stack_frame: &mut InterruptStackFrame, regs: &mut Registers);
+type HandlerClosure = dyn Fn(&mut InterruptStackFrame, &mut Registers) + Send + Sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptError {
+    /// The vector has no dispatch stub, either because it is an exception or because it is
+    /// wired up statically in the IDT.
+    UnsupportedVector(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    fn new(vector: u8) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        HandlerId {
+            vector,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+enum Handler {
+    Function {
+        handler: HandlerFn,
+        context: *mut (),
+    },
+    Closure(Box<HandlerClosure>),
+}
+
+struct Registration {
+    id: HandlerId,
+    handler: Handler,
+    /// Set by [`unregister`]. The registration is only dropped once nothing dispatches to it,
+    /// as a handler may unregister itself.
+    removed: AtomicBool,
+}
+
+// The context pointer is owned by whoever registered the handler, who promises it stays valid
+// until the handler is unregistered.
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+static HANDLERS: [RwLock<Vec<Registration>>; 256] = [const { RwLock::new(Vec::new()) }; 256];
+static ALLOCATED: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
+
+pub fn is_dynamic(vector: u8) -> bool {
+    (FIRST_DYNAMIC_VECTOR..END_DYNAMIC_VECTOR).contains(&vector)
+}
+
+/// Returns the vector a legacy PIC line is remapped to.
+pub fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Reserves a vector that is not connected to the PICs, e.g. for MSI capable devices.
+pub fn allocate_vector() -> Option<u8> {
+    (FIRST_FREE_VECTOR..END_DYNAMIC_VECTOR).find(|&vector| {
+        ALLOCATED[vector as usize]
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    })
+}
+
+/// Returns a vector obtained from [`allocate_vector`]. Any handlers still registered on it are
+/// dropped. Returns `false`, leaving the vector alone, if it was not allocated.
+pub fn free_vector(vector: u8) -> bool {
+    if !ALLOCATED[vector as usize].load(Ordering::SeqCst) {
+        return false;
+    }
+    without_interrupts(|| HANDLERS[vector as usize].write().clear());
+    ALLOCATED[vector as usize].store(false, Ordering::SeqCst);
+    true
+}
+
+/// Registers `handler` to be called with `context` whenever `vector` fires. Several handlers
+/// may share a vector, in which case all of them are called in registration order.
+pub fn register_handler(
+    vector: u8,
+    handler: HandlerFn,
+    context: *mut (),
+) -> Result<HandlerId, InterruptError> {
+    register(vector, Handler::Function { handler, context })
+}
+
+/// Like [`register_handler`], but the handler is a closure that owns whatever state it needs
+/// instead of getting a context pointer.
+pub fn register_closure(
+    vector: u8,
+    handler: impl Fn(&mut InterruptStackFrame, &mut Registers) + Send + Sync + 'static,
+) -> Result<HandlerId, InterruptError> {
+    register(vector, Handler::Closure(Box::new(handler)))
+}
+
+/// Removes a handler, returning whether it was still registered. The interrupt line is masked
+/// once its last handler is gone. Handlers may unregister themselves.
+pub fn unregister(id: HandlerId) -> bool {
+    let vector = id.vector;
+    without_interrupts(|| {
+        let handlers = HANDLERS[vector as usize].read();
+        let removed = handlers.iter().any(|registration| {
+            registration.id == id && !registration.removed.swap(true, Ordering::SeqCst)
+        });
+        let unused = handlers
+            .iter()
+            .all(|registration| registration.removed.load(Ordering::SeqCst));
+        drop(handlers);
+        if removed && unused && vector < FIRST_FREE_VECTOR {
+            mask_irq(vector - PIC_1_OFFSET);
+        }
+        remove_unregistered(vector);
+        removed
+    })
+}
+
+/// Drops the registrations [`unregister`] marked, unless `dispatch` is running the vector's
+/// handlers. Those are dropped by the next call instead.
+fn remove_unregistered(vector: u8) {
+    if let Some(mut handlers) = HANDLERS[vector as usize].try_write() {
+        handlers.retain(|registration| !registration.removed.load(Ordering::SeqCst));
+    }
+}
+
+fn register(vector: u8, handler: Handler) -> Result<HandlerId, InterruptError> {
+    if !is_dynamic(vector) {
+        return Err(InterruptError::UnsupportedVector(vector));
+    }
+
+    let id = HandlerId::new(vector);
+    // Interrupts stay off while the write lock is held, otherwise `dispatch` could spin on it
+    // forever.
+    without_interrupts(|| {
+        remove_unregistered(vector);
+        HANDLERS[vector as usize].write().push(Registration {
+            id,
+            handler,
+            removed: AtomicBool::new(false),
+        });
+        if vector < FIRST_FREE_VECTOR {
+            unmask_irq(vector - PIC_1_OFFSET);
+        }
+    });
+    Ok(id)
+}
+
+fn mask_irq(irq: u8) {
+    let _pics = PICS.lock();
+    let (port, line) = if irq < 8 {
+        (0x21, irq)
+    } else {
+        (0xA1, irq - 8)
+    };
+    let mut data: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = data.read();
+        data.write(mask | 1 << line);
+    }
+}
+
+fn unmask_irq(irq: u8) {
+    let _pics = PICS.lock();
+    let (port, line) = if irq < 8 {
+        (0x21, irq)
+    } else {
+        (0xA1, irq - 8)
+    };
+    let mut data: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = data.read();
+        data.write(mask & !(1 << line));
+        if irq >= 8 {
+            // The slave PIC is cascaded through line 2 of the master
+            let mut master: Port<u8> = Port::new(0x21);
+            let mask = master.read();
+            master.write(mask & !(1 << 2));
+        }
+    }
+}
+
+pub(super) fn dispatch(vector: u8, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    for registration in HANDLERS[vector as usize].read().iter() {
+        if registration.removed.load(Ordering::SeqCst) {
+            continue;
+        }
+        match &registration.handler {
+            Handler::Function { handler, context } => handler(*context, stack_frame, regs),
+            Handler::Closure(handler) => handler(stack_frame, regs),
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use crate::task::scheduler::{self, add_paused_thread, current_thread};
+use crate::debug::unwind::record_interrupt;
+use crate::debug::{gdbstub, lockdep};
+use crate::sync::SpinLock;
+use crate::task::scheduler::{self, current_thread};
 use crate::task::thread::Registers;
-use crate::{gdt, get_kernel_cr3, serial_println, println};
+use crate::task::timer;
+use crate::{gdt, get_kernel_cr3, time};
 use core::arch::asm;
 use core::mem::size_of;
 use lazy_static::lazy_static;
@@ -10,6 +14,8 @@ use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use x86_64::VirtAddr;
 
+pub mod dynamic;
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
@@ -28,13 +34,13 @@ macro_rules! pop_registers {
 }
 
 macro_rules! register_interrupt {
-    ($idt:ident, $interrupt:path => $handler:ident) => {{
+    ($idt:ident, $interrupt:expr => $handler:ident) => {{
         #[allow(unused)]
         const CHECK_HANDLER: fn(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) = $handler;
         extern "C" fn as_kernel(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
             let (current_cr3, _) = Cr3::read();
             unsafe { Cr3::write(get_kernel_cr3(), Cr3Flags::empty()) };
-            $handler(stack_frame, regs);
+            in_handler($interrupt as u8, || $handler(stack_frame, regs));
             unsafe { Cr3::write(current_cr3, Cr3Flags::empty()) };
         }
         #[naked]
@@ -42,15 +48,16 @@ macro_rules! register_interrupt {
             unsafe {
                 asm!(
                     push_registers!(),
+                    // The end of interrupt is signalled first, as the handler may switch to
+                    // another thread and only come back much later
                     "
-                    mov rdi, rsp
-                    add rdi, {regs_size}
-                    mov rsi, rsp
                     sub rsp, 0x8
                     cld
-                    call {handler}
                     mov rdi, {interrupt_index}
                     call {end_interrupt}
+                    lea rdi, [rsp + 0x8 + {regs_size}]
+                    lea rsi, [rsp + 0x8]
+                    call {handler}
                     add rsp, 0x8
                     ",
                     pop_registers!(),
@@ -68,14 +75,32 @@ macro_rules! register_interrupt {
     }};
 }
 
+macro_rules! register_dynamic_vectors {
+    ($idt:ident; $($vector:literal),* $(,)?) => {$(
+        {
+            fn dispatch(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+                dynamic::dispatch($vector, stack_frame, regs);
+            }
+            register_interrupt!($idt, $vector => dispatch);
+        }
+    )*};
+}
+
+/// Panics from an exception handler, keeping track of the faulting instruction so the panic
+/// backtrace starts there.
+macro_rules! exception_panic {
+    ($stack_frame:expr, $($arg:tt)*) => {{
+        crate::debug::unwind::record_exception(&$stack_frame);
+        panic!($($arg)*)
+    }};
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.divide_error.set_handler_fn(divide_error);
-        idt.debug.set_handler_fn(debug);
         idt.non_maskable_interrupt
             .set_handler_fn(non_maskable_interrupt);
-        idt.breakpoint.set_handler_fn(breakpoint);
         idt.overflow.set_handler_fn(overflow);
         idt.bound_range_exceeded
             .set_handler_fn(bound_range_exceeded);
@@ -105,19 +130,27 @@ lazy_static! {
         idt.vmm_communication_exception
             .set_handler_fn(vmm_communication_exception);
         idt.security_exception.set_handler_fn(security_exception);
-        unsafe {
-            register_interrupt!(idt, InterruptIndex::Timer => timer)
-                .set_stack_index(gdt::TIMER_IST_INDEX);
-        }
+        register_interrupt!(idt, InterruptIndex::Timer => timer);
+        register_interrupt!(idt, 1 => debug);
+        register_interrupt!(idt, 3 => breakpoint);
         register_interrupt!(idt, InterruptIndex::Keyboard => keyboard_interrupt_handler);
         register_interrupt!(idt, InterruptIndex::Syscall => syscall_handler)
             .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        register_dynamic_vectors!(idt;
+            34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+            48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+            64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+            80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+            96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+            112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127,
+        );
 
         idt
     };
 }
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> = SpinLock::new("pics", unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
 
 pub struct APIC;
 
@@ -131,55 +164,63 @@ pub fn init() {
 }
 
 extern "x86-interrupt" fn divide_error(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn debug(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    exception_panic!(stack_frame, "EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: Non-Maskable Interrupt\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn breakpoint(_stack_frame: InterruptStackFrame) {
-    #[cfg(not(test))]
-    panic!("EXCEPTION: BREAKPOINT\n{:#?}", _stack_frame);
+    exception_panic!(
+        stack_frame,
+        "EXCEPTION: Non-Maskable Interrupt\n{:#?}",
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn overflow(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+    exception_panic!(stack_frame, "EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn bound_range_exceeded(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
+    exception_panic!(
+        stack_frame,
+        "EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}",
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+    exception_panic!(stack_frame, "EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn device_not_available(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
+extern "x86-interrupt" fn device_not_available(_stack_frame: InterruptStackFrame) {
+    let (current_cr3, _) = Cr3::read();
+    unsafe { Cr3::write(get_kernel_cr3(), Cr3Flags::empty()) };
+    crate::fpu::handle_device_not_available();
+    unsafe { Cr3::write(current_cr3, Cr3Flags::empty()) };
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{stack_frame:#?}");
+    exception_panic!(stack_frame, "EXCEPTION: DOUBLE FAULT\n{stack_frame:#?}");
 }
 
 extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!("EXCEPTION({error_code}): INVALID TSS\n{:#?}", stack_frame);
+    exception_panic!(
+        stack_frame,
+        "EXCEPTION({error_code}): INVALID TSS\n{:#?}",
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn segment_not_present(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): SEGMENT NOT PRESENT\n{:#?}",
         stack_frame
     );
 }
 
 extern "x86-interrupt" fn stack_segment_fault(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): STACK SEGMENT FAULT\n{:#?}",
         stack_frame
     );
@@ -192,7 +233,8 @@ extern "x86-interrupt" fn general_protection_fault(
     unsafe {
         Cr3::write(get_kernel_cr3(), Cr3Flags::empty());
     }
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): GENERAL PROTECTION FAULT\n{:#?}",
         stack_frame
     );
@@ -207,15 +249,19 @@ extern "x86-interrupt" fn page_fault(
         Cr3::write(get_kernel_cr3(), Cr3Flags::empty());
     }
 
-    panic!(
+    let tid = current_thread();
+    let name = scheduler::thread_info(tid).map(|info| info.name);
+    exception_panic!(
+        stack_frame,
         indoc::indoc! {"
-         \nThread id: {}
+         \nThread: {} ({})
          Exception: PAGE FAULT
          Accessed Address: {:?}
          Error code: {:?}
          {:#?}
         "},
-        current_thread().as_u64(),
+        tid.as_u64(),
+        name.as_deref().unwrap_or("?"),
         Cr2::read(),
         error_code,
         stack_frame,
@@ -223,71 +269,106 @@ extern "x86-interrupt" fn page_fault(
 }
 
 extern "x86-interrupt" fn x87_floating_point(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
+    exception_panic!(
+        stack_frame,
+        "EXCEPTION: X87 FLOATING POINT\n{:#?}",
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn alignment_check(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): ALIGNMENT CHECK\n{:#?}",
         stack_frame
     );
 }
 
 extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
-    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+    exception_panic!(stack_frame, "EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn simd_floating_point(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+    exception_panic!(
+        stack_frame,
+        "EXCEPTION: SIMD FLOATING POINT\n{:#?}",
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn virtualization(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: VIRTUALIZATION\n{:#?}", stack_frame);
+    exception_panic!(stack_frame, "EXCEPTION: VIRTUALIZATION\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn vmm_communication_exception(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): VMM COMMUNICATION EXCEPTION\n{:#?}",
         stack_frame
     );
 }
 
 extern "x86-interrupt" fn security_exception(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
+    exception_panic!(
+        stack_frame,
         "EXCEPTION({error_code}): SECURITY EXCEPTION\n{:#?}",
         stack_frame
     );
 }
 
-fn timer(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
-    if let Some(thread) = scheduler::schedule() {
-        unsafe {
-            stack_frame.as_mut().update(|frame| {
-                add_paused_thread(frame, regs, thread);
-            });
-        }
+fn timer(_stack_frame: &mut InterruptStackFrame, _regs: &mut Registers) {
+    time::tick();
+    scheduler::wake_sleepers();
+    timer::wake_expired();
+    scheduler::preempt();
+}
+
+fn debug(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    if !gdbstub::handle_debug(stack_frame, regs) {
+        record_interrupt(stack_frame, regs);
+        panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    }
+}
+
+fn breakpoint(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    if gdbstub::handle_breakpoint(stack_frame, regs) {
+        return;
+    }
+    #[cfg(not(test))]
+    {
+        record_interrupt(stack_frame, regs);
+        panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
     }
 }
 
 fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame, _regs: &mut Registers) {
-    use x86_64::instructions::port::Port;
+    crate::ps2::handle_interrupt();
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
+fn syscall_handler(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    crate::syscall::handle(stack_frame, regs);
 }
 
-fn syscall_handler(_stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
-    serial_println!("syscall!");
-    println!("syscall!");
-    println!("User rax: {}", regs.rax);
+extern "C" fn interrupt_return(interrupt: u8) {
+    in_handler(interrupt, || unsafe {
+        PICS.lock().notify_end_of_interrupt(interrupt)
+    });
 }
 
-extern "C" fn interrupt_return(interrupt: InterruptIndex) {
-    unsafe { PICS.lock().notify_end_of_interrupt(interrupt as u8) }
+/// Runs `f` on behalf of the interrupt, letting the lock validator know when it is a hardware
+/// interrupt that may arrive in the middle of any code running with interrupts enabled.
+fn in_handler(interrupt: u8, f: impl FnOnce()) {
+    let hardware = (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&interrupt);
+    if hardware {
+        lockdep::enter_interrupt();
+    }
+    f();
+    if hardware {
+        lockdep::exit_interrupt();
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -300,8 +381,65 @@ pub enum InterruptIndex {
 
 #[cfg(test)]
 mod tests {
+    use super::dynamic;
+    use core::arch::asm;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
     #[test_case]
     fn test_breakpoint_exception() {
         x86_64::instructions::interrupts::int3();
     }
+
+    #[test_case]
+    fn test_shared_dynamic_handlers() {
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        let first = dynamic::register_closure(127, |_, _| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        let second = dynamic::register_closure(127, |_, _| {
+            CALLS.fetch_add(10, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        unsafe { asm!("int 127") };
+        assert_eq!(CALLS.load(Ordering::SeqCst), 11);
+
+        assert!(dynamic::unregister(first));
+        unsafe { asm!("int 127") };
+        assert_eq!(CALLS.load(Ordering::SeqCst), 21);
+
+        assert!(dynamic::unregister(second));
+        assert!(!dynamic::unregister(second));
+    }
+
+    #[test_case]
+    fn test_allocate_vector() {
+        let vector = dynamic::allocate_vector().unwrap();
+        assert!(vector >= dynamic::FIRST_FREE_VECTOR);
+        let other = dynamic::allocate_vector().unwrap();
+        assert_ne!(vector, other);
+        assert!(dynamic::free_vector(other));
+        assert!(dynamic::free_vector(vector));
+        assert!(!dynamic::free_vector(vector));
+        assert!(!dynamic::free_vector(dynamic::irq_vector(12)));
+    }
+
+    #[test_case]
+    fn test_handler_unregisters_itself() {
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        static HANDLER: spin::Once<dynamic::HandlerId> = spin::Once::new();
+        let handler = *HANDLER.call_once(|| {
+            dynamic::register_closure(127, |_, _| {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                assert!(dynamic::unregister(*HANDLER.get().unwrap()));
+            })
+            .unwrap()
+        });
+
+        unsafe { asm!("int 127") };
+        unsafe { asm!("int 127") };
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(!dynamic::unregister(handler));
+    }
 }
@@ -8,23 +8,31 @@
 #![feature(naked_functions)]
 #![feature(asm_sym)]
 #![feature(asm_const)]
+#![feature(inline_const)]
 #![test_runner(crate::tests::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
 pub mod allocator;
+pub mod debug;
+pub mod fpu;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod ps2;
 pub mod serial;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod time;
 pub mod vga;
 
 use bootloader::boot_info::MemoryRegions;
 use bootloader::BootInfo;
 use core::alloc::Layout;
 use spin::Once;
+use task::policy::Policy;
 use task::scheduler;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::PhysFrame;
@@ -70,7 +78,32 @@ fn test_kernel_main(bootinfo: &'static mut BootInfo) -> ! {
     hlt_loop();
 }
 
+/// Choices made at boot, see [`init_with_config`].
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub scheduling_policy: Policy,
+    /// How many times a second the timer interrupts, which is how often threads can be
+    /// preempted and how precisely they can sleep.
+    pub timer_hz: u32,
+    /// Stops the periodic timer while no thread can run, instead waking up on every tick.
+    pub tickless: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scheduling_policy: Policy::default(),
+            timer_hz: time::DEFAULT_HZ,
+            tickless: true,
+        }
+    }
+}
+
 pub fn init(boot_info: &'static mut BootInfo) {
+    init_with_config(boot_info, Config::default());
+}
+
+pub fn init_with_config(boot_info: &'static mut BootInfo, config: Config) {
     KERNEL_INFO.call_once(|| KernelInfo {
         cr3: Cr3::read().0,
         memory_regions: &boot_info.memory_regions,
@@ -85,6 +118,7 @@ pub fn init(boot_info: &'static mut BootInfo) {
     serial_println!("made it");
     vga::init_vga();
     serial_println!("made it");
+    time::init(config.timer_hz, config.tickless);
     interrupts::init();
     //TODO move this one up in the order
     serial_println!("made it");
@@ -92,7 +126,12 @@ pub fn init(boot_info: &'static mut BootInfo) {
     serial_println!("made it");
     memory::init_memory();
     allocator::init_heap().expect("Heap initalization failed");
-    scheduler::init_scheduler();
+    fpu::init();
+    scheduler::init_scheduler(config.scheduling_policy);
+    match ps2::init() {
+        Ok(devices) => serial_println!("PS/2: {:?}", devices),
+        Err(error) => serial_println!("PS/2: {}", error),
+    }
 }
 
 pub fn hlt_loop() -> ! {
@@ -149,6 +188,7 @@ pub mod tests {
     pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
         serial_println!("{}", "[FAILED]".red());
         serial_println!("Error: {info}\n");
+        crate::debug::print_panic_backtrace();
         exit_qemu(QemuExitCode::Failed);
         hlt_loop();
     }
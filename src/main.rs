@@ -6,29 +6,67 @@
 
 extern crate alloc;
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::arch::asm;
 
 use bootloader::{entry_point, BootInfo};
 use os::{
-    print, println, serial_println,
-    task::{executor::Executor, keyboard, scheduler, Task},
+    println, serial_print, serial_println,
+    task::{
+        executor::Executor,
+        keyboard::{self, Layout},
+        line_editor::LineEditor,
+        policy::Policy,
+        policy::Priority,
+        scheduler, TaskPriority,
+    },
+    Config,
 };
-use pc_keyboard::DecodedKey;
 
 entry_point!(kernel_main);
 
-async fn print_keypresses() {
+const COMMANDS: [&str; 3] = ["echo", "help", "layout"];
+
+async fn shell() {
+    let mut editor = LineEditor::new();
+    editor.set_completer(complete_command);
     loop {
-        match os::task::keyboard::recv().await {
-            DecodedKey::Unicode(c) => {
-                serial_println!("printing {}", c);
-                print!("{}", c);
-            }
-            DecodedKey::RawKey(key) => print!("{:?}", key),
+        let line = editor.read_line("> ").await;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("echo") => println!("{}", words.collect::<Vec<_>>().join(" ")),
+            Some("help") => println!("commands: {}", COMMANDS.join(" ")),
+            Some("layout") => match words.next() {
+                Some(name) => match Layout::from_name(name) {
+                    Some(layout) => keyboard::set_layout(layout),
+                    None => println!("unknown layout: {}", name),
+                },
+                None => println!("layout: {}", keyboard::layout().name()),
+            },
+            Some(command) => println!("unknown command: {}", command),
+            None => {}
         }
     }
 }
 
+/// Completes command names, and the layout names after `layout`.
+fn complete_command(line: &str, word: &str) -> Vec<String> {
+    let line = line.trim_start();
+    let options: Vec<&str> = if line == word {
+        COMMANDS.to_vec()
+    } else if line.starts_with("layout ") {
+        Layout::ALL.iter().map(|layout| layout.name()).collect()
+    } else {
+        Vec::new()
+    };
+    options
+        .into_iter()
+        .filter(|option| option.starts_with(word))
+        .map(String::from)
+        .collect()
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -38,7 +76,7 @@ async fn example_task() {
     println!("async number: {}", number);
 }
 
-fn user_task() -> ! {
+fn user_task() {
     unsafe {
         asm!(
             "
@@ -54,7 +92,13 @@ fn user_task() -> ! {
 }
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    os::init(boot_info);
+    os::init_with_config(
+        boot_info,
+        Config {
+            scheduling_policy: Policy::FairShare,
+            ..Config::default()
+        },
+    );
     if cfg!(test) {
         #[cfg(test)]
         test_main();
@@ -70,6 +114,10 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     println!("Hey there");
     serial_println!("Hey there");
+    serial_println!("Scheduling policy: {}", scheduler::policy_name());
+    if os::debug::gdbstub::init() {
+        serial_println!("GDB stub listening on COM2");
+    }
 
     //unsafe {
     //    let mut mapper = memory::init();
@@ -82,10 +130,14 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     //    }
     //}
 
-    scheduler::spawn(|| loop {
-        slow();
-        print!("2");
-    });
+    // Busy threads must not keep the executor below from handling key presses
+    scheduler::Builder::new()
+        .name("busy loop")
+        .priority(Priority::LOW)
+        .spawn(|| loop {
+            slow();
+            serial_print!("2");
+        });
     //scheduler::spawn(|| loop {
     //    slow();
     //    print!("3");
@@ -104,9 +156,10 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     //});
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::keyboard_scheduler()));
-    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn(example_task());
+    // Handling key presses comes before anything else the executor has to do
+    executor.spawn_with_priority(TaskPriority::High, keyboard::keyboard_scheduler());
+    executor.spawn_with_priority(TaskPriority::High, shell());
     executor.run();
 }
 
@@ -127,7 +180,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    serial_println!("{}", info);
-    println!("{}", info);
+    os::debug::crash::report(info);
     os::hlt_loop();
 }
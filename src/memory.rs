@@ -1,31 +1,33 @@
 use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
-use spin::{Mutex, MutexGuard, Once};
+use spin::Once;
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+use crate::sync::{SpinLock, SpinLockGuard};
 use crate::{get_memory_regions, get_physical_memory_offset, serial_println};
 
-static FRAME_ALLOCATOR: Once<Mutex<BootInfoFrameAllocator>> = Once::new();
-static KERNEL_MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<SpinLock<BootInfoFrameAllocator>> = Once::new();
+static KERNEL_MAPPER: Once<SpinLock<OffsetPageTable<'static>>> = Once::new();
 
-pub fn lock_frame_allocator<'a>() -> MutexGuard<'a, BootInfoFrameAllocator> {
+pub fn lock_frame_allocator<'a>() -> SpinLockGuard<'a, BootInfoFrameAllocator> {
     FRAME_ALLOCATOR.get().unwrap().lock()
 }
 
-pub fn lock_memory_mapper<'a>() -> MutexGuard<'a, OffsetPageTable<'static>> {
+pub fn lock_memory_mapper<'a>() -> SpinLockGuard<'a, OffsetPageTable<'static>> {
     KERNEL_MAPPER.get().unwrap().lock()
 }
 
 pub fn init_memory() {
     let frame_allocator = unsafe { BootInfoFrameAllocator::new(get_memory_regions()) };
-    FRAME_ALLOCATOR.call_once(|| Mutex::new(frame_allocator));
+    FRAME_ALLOCATOR.call_once(|| SpinLock::new("frame allocator", frame_allocator));
     let mapper = unsafe { init() };
-    KERNEL_MAPPER.call_once(|| Mutex::new(mapper));
+    KERNEL_MAPPER.call_once(|| SpinLock::new("kernel mapper", mapper));
 }
 
 pub fn print_page_table(mapper: &mut OffsetPageTable<'static>) {
@@ -52,6 +54,9 @@ pub fn print_page_table(mapper: &mut OffsetPageTable<'static>) {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
+    /// Frames handed back through [`FrameDeallocator`]. Each one stores the next in its first
+    /// bytes, so the list needs no memory of its own.
+    free_list: Option<PhysFrame>,
 }
 
 unsafe impl Send for BootInfoFrameAllocator {}
@@ -61,6 +66,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
         }
     }
 
@@ -72,26 +78,78 @@ impl BootInfoFrameAllocator {
             .flat_map(|r| r.step_by(4096))
             .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    fn free_list_link(frame: PhysFrame) -> *mut Option<PhysFrame> {
+        (frame.start_address().as_u64() + get_physical_memory_offset()) as *mut Option<PhysFrame>
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            self.free_list = unsafe { Self::free_list_link(frame).read() };
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        Self::free_list_link(frame).write(self.free_list.take());
+        self.free_list = Some(frame);
+    }
+}
+
+/// Number of pages mapped from address 0 in a user address space.
+pub const USER_PAGES: usize = 16;
+
+/// The frames owned by a user address space: its page tables and the pages mapped from
+/// address 0. The kernel tables it links to are shared and stay untouched.
+#[derive(Debug)]
+pub struct UserAddressSpace {
+    pub l4_frame: PhysFrame,
+    tables: [PhysFrame; 3],
+    pages: [PhysFrame; USER_PAGES],
+}
+
+impl UserAddressSpace {
+    /// Returns a kernel pointer to the user page mapped at `index * 4096`.
+    pub fn page_ptr(&self, index: usize) -> *mut u8 {
+        (self.pages[index].start_address().as_u64() + get_physical_memory_offset()) as *mut u8
+    }
+
+    /// Returns every frame to `frame_deallocator`.
+    ///
+    /// The address space must not be active or in use by any thread.
+    pub unsafe fn free(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        let frames = self.pages.into_iter().chain(self.tables);
+        for frame in frames.chain([self.l4_frame]) {
+            frame_deallocator.deallocate_frame(frame);
+        }
+    }
+}
+
 pub fn allocate_page_table(
     mapper: &mut OffsetPageTable<'static>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> (PhysFrame, PhysAddr) {
+) -> UserAddressSpace {
     let physical_memory_offset = get_physical_memory_offset();
+    // Freed frames are reused, so nothing can be assumed to be zeroed
+    let mut allocate_zeroed = || {
+        let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+        let page = (frame.start_address().as_u64() + physical_memory_offset) as *mut u8;
+        unsafe { page.write_bytes(0, 4096) };
+        frame
+    };
 
-    let l4_frame = frame_allocator.allocate_frame().unwrap();
-    let l3_frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
-    let l2_frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
-    let l1_frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+    let l4_frame = allocate_zeroed();
+    let l3_frame = allocate_zeroed();
+    let l2_frame = allocate_zeroed();
+    let l1_frame = allocate_zeroed();
+    let pages = [(); USER_PAGES].map(|_| allocate_zeroed());
 
     let kernel_page_table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
     let user_page_table_flags =
@@ -119,12 +177,15 @@ pub fn allocate_page_table(
     l3_table[510].set_addr(kernel_data, kernel_page_table_flags);
     l3_table[511].set_addr(kernel_code, kernel_page_table_flags);
 
-    l1_table.iter_mut().take(16).for_each(|entry| {
-        let frame = frame_allocator.allocate_frame().unwrap();
+    for (entry, frame) in l1_table.iter_mut().zip(pages) {
         entry.set_addr(frame.start_address(), user_page_table_flags);
-    });
+    }
 
-    (l4_frame, l1_table[0].addr())
+    UserAddressSpace {
+        l4_frame,
+        tables: [l3_frame, l2_frame, l1_frame],
+        pages,
+    }
 }
 
 fn get_kernel_level_3_tables(mapper: &mut OffsetPageTable<'static>) -> (PhysAddr, PhysAddr) {
@@ -0,0 +1,206 @@
+//! Commands for the keyboard on the first port.
+
+use super::{
+    with_controller, Controller, DeviceType, Ps2Error, Ps2Port, CONFIG_FIRST_IRQ,
+    CONFIG_TRANSLATION, DEVICE_ENABLE_SCANNING,
+};
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
+
+const SET_LEDS: u8 = 0xed;
+const SCANCODE_SET: u8 = 0xf0;
+/// Asks [`SCANCODE_SET`] for the current set instead of changing it.
+const GET_SCANCODE_SET: u8 = 0;
+const SET_TYPEMATIC: u8 = 0xf3;
+
+/// The scancode set the keyboard sends, which is what the keys are decoded with.
+static SCANCODE_SET_IN_USE: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+
+/// How key presses are encoded. Without a driver, the controller translates set 2, which every
+/// keyboard speaks, to set 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+}
+
+/// The lock lights on the keyboard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+/// How keys repeat while they are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// How long a key has to be held before it repeats, from 250 ms to 1 s in steps of 250 ms.
+    pub delay: Duration,
+    /// Repeats per second, from 2 to 30.
+    pub rate: u32,
+}
+
+impl Typematic {
+    /// The byte the keyboard takes, with the closest delay and rate it supports.
+    fn encode(self) -> u8 {
+        let delay = (self.delay.as_millis() / 250).clamp(1, 4) as u8 - 1;
+        let wanted = self.rate as u64 * 1000;
+        // A code repeats every (8 + low bits) * 2^(high bits) * 4.17 ms
+        let rate = (0..32u8)
+            .min_by_key(|code| {
+                let period = (8 + (code & 7) as u64) * (1 << (code >> 3)) * 417;
+                let millihertz = 100_000_000 / period;
+                millihertz.abs_diff(wanted)
+            })
+            .unwrap();
+        delay << 5 | rate
+    }
+}
+
+impl Default for Typematic {
+    fn default() -> Self {
+        Typematic {
+            delay: Duration::from_millis(500),
+            rate: 10,
+        }
+    }
+}
+
+/// Sets up the keyboard [`init`](super::init) found on the first port, and turns on its
+/// interrupts. They are turned on even if a setting fails.
+pub(super) fn setup(controller: &mut Controller) -> Result<(), Ps2Error> {
+    let configured = configure(controller);
+    // A keyboard that did not take every setting still types
+    let enabled = controller.device_command(Ps2Port::First, &[DEVICE_ENABLE_SCANNING]);
+    let irq = controller.set_config_bits(CONFIG_FIRST_IRQ, true);
+    configured.and(enabled).and(irq)
+}
+
+/// Picks the scancode set and sets the lights and the typematic rate.
+fn configure(controller: &mut Controller) -> Result<(), Ps2Error> {
+    // Set 2 as it comes from the keyboard, or translated to set 1 if it cannot be picked
+    let set = match controller.set_scancode_set(ScancodeSet::Set2) {
+        Ok(()) => ScancodeSet::Set2,
+        Err(_) => ScancodeSet::Set1,
+    };
+    controller.set_config_bits(CONFIG_TRANSLATION, set == ScancodeSet::Set1)?;
+    SCANCODE_SET_IN_USE.store(set as u8, Ordering::SeqCst);
+    controller.device_command(Ps2Port::First, &[SET_LEDS, Leds::default().bits()])?;
+    controller.device_command(
+        Ps2Port::First,
+        &[SET_TYPEMATIC, Typematic::default().encode()],
+    )
+}
+
+impl Controller {
+    fn keyboard(&mut self) -> Result<(), Ps2Error> {
+        match self.device(Ps2Port::First) {
+            Ok(DeviceType::Keyboard) => Ok(()),
+            Err(Ps2Error::NotInitialized) => Err(Ps2Error::NotInitialized),
+            _ => Err(Ps2Error::NoKeyboard),
+        }
+    }
+
+    fn set_scancode_set(&mut self, set: ScancodeSet) -> Result<(), Ps2Error> {
+        self.device_command(Ps2Port::First, &[SCANCODE_SET, set as u8])
+    }
+}
+
+pub fn is_present() -> bool {
+    with_controller(|controller| controller.keyboard().is_ok())
+}
+
+/// Turns the lock lights on or off.
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        controller.keyboard()?;
+        controller.device_command(Ps2Port::First, &[SET_LEDS, leds.bits()])
+    })
+}
+
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        controller.keyboard()?;
+        controller.device_command(Ps2Port::First, &[SET_TYPEMATIC, typematic.encode()])
+    })
+}
+
+/// Switches the scancode set the keyboard sends. Key events are decoded with the new one from
+/// the next key on.
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        controller.keyboard()?;
+        controller.set_scancode_set(set)?;
+        // Translation would garble anything but set 2
+        controller.set_config_bits(CONFIG_TRANSLATION, false)?;
+        SCANCODE_SET_IN_USE.store(set as u8, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// Asks the keyboard which scancode set it sends.
+pub fn query_scancode_set() -> Result<u8, Ps2Error> {
+    with_controller(|controller| {
+        controller.keyboard()?;
+        controller.device_command(Ps2Port::First, &[SCANCODE_SET, GET_SCANCODE_SET])?;
+        controller.read_from(Ps2Port::First, super::TIMEOUT)
+    })
+}
+
+/// The scancode set key presses arrive in, after any translation by the controller.
+pub fn scancode_set() -> ScancodeSet {
+    match SCANCODE_SET_IN_USE.load(Ordering::SeqCst) {
+        2 => ScancodeSet::Set2,
+        _ => ScancodeSet::Set1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn typematic_picks_the_closest_setting() {
+        let encode = |millis, rate| {
+            Typematic {
+                delay: Duration::from_millis(millis),
+                rate,
+            }
+            .encode()
+        };
+        assert_eq!(encode(250, 30), 0x00);
+        assert_eq!(encode(1000, 2), 0x7f);
+        assert_eq!(encode(500, 10), 0x2c);
+        // Out of range values are clamped
+        assert_eq!(encode(0, 100), 0x00);
+        assert_eq!(encode(5000, 0), 0x7f);
+    }
+
+    #[test_case]
+    fn leds_are_set() {
+        assert_eq!(
+            Leds {
+                caps_lock: true,
+                ..Leds::default()
+            }
+            .bits(),
+            0b100
+        );
+        assert_eq!(set_leds(Leds::default()), Ok(()));
+        assert_eq!(set_typematic(Typematic::default()), Ok(()));
+    }
+
+    #[test_case]
+    fn keyboard_sends_scancode_set_2() {
+        assert_eq!(scancode_set(), ScancodeSet::Set2);
+        assert_eq!(query_scancode_set(), Ok(2));
+    }
+}
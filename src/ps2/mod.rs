@@ -0,0 +1,470 @@
+//! The i8042 PS/2 controller, which the keyboard and the mouse are attached to.
+//!
+//! [`init`] tests the controller, finds the devices on its two ports and sets up the keyboard
+//! and the mouse. The controller is only used with interrupts disabled, as the interrupt
+//! handlers read from it too.
+
+pub mod keyboard;
+pub mod mouse;
+
+use crate::sync::SpinLock;
+use core::fmt;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+/// Reading it gives the status, writing it sends a command to the controller itself.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte waiting to be read came from the second port.
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translates scancode set 2 from the first port to set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+/// Sends the next data byte to the second port instead of the first.
+const WRITE_SECOND: u8 = 0xd4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_RESET_PASSED: u8 = 0xaa;
+
+/// Status polls before giving up on the controller or a device.
+const TIMEOUT: u32 = 100_000;
+/// Devices test themselves after a reset, which takes much longer.
+const RESET_TIMEOUT: u32 = 5_000_000;
+/// How often a device may ask for a byte again.
+const RETRIES: u32 = 3;
+
+static CONTROLLER: SpinLock<Controller> = SpinLock::new("ps2 controller", Controller::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Keyboard,
+    Mouse,
+    /// A mouse with a scroll wheel.
+    WheelMouse,
+    /// A mouse with a scroll wheel and two extra buttons.
+    FiveButtonMouse,
+    /// Answered identify with this first byte.
+    Unknown(u8),
+}
+
+impl DeviceType {
+    /// Interprets what a device answered identify with.
+    fn from_id(id: &[u8]) -> DeviceType {
+        match id {
+            // AT keyboards do not answer at all
+            [] => DeviceType::Keyboard,
+            [0xab, ..] | [0xac, ..] => DeviceType::Keyboard,
+            [0x00] => DeviceType::Mouse,
+            [0x03] => DeviceType::WheelMouse,
+            [0x04] => DeviceType::FiveButtonMouse,
+            [first, ..] => DeviceType::Unknown(*first),
+        }
+    }
+
+    pub fn is_mouse(self) -> bool {
+        matches!(
+            self,
+            DeviceType::Mouse | DeviceType::WheelMouse | DeviceType::FiveButtonMouse
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or a device did not answer in time.
+    Timeout,
+    /// The controller's self-test answered with this instead of passing.
+    ControllerTestFailed(u8),
+    PortTestFailed(Ps2Port, u8),
+    /// Nothing answered on the port, or the controller only has one.
+    NoDevice(Ps2Port),
+    /// There is no keyboard on the first port.
+    NoKeyboard,
+    /// The device kept asking for a byte again.
+    Resend,
+    /// The device answered with something else than the command calls for.
+    UnexpectedResponse(u8),
+    /// [`init`] has not run yet.
+    NotInitialized,
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ps2Error::Timeout => write!(f, "timed out"),
+            Ps2Error::ControllerTestFailed(response) => {
+                write!(f, "controller self-test failed with {response:#04x}")
+            }
+            Ps2Error::PortTestFailed(port, response) => {
+                write!(f, "{port:?} port test failed with {response:#04x}")
+            }
+            Ps2Error::NoDevice(port) => write!(f, "no device on the {port:?} port"),
+            Ps2Error::NoKeyboard => write!(f, "no keyboard"),
+            Ps2Error::Resend => write!(f, "device kept asking for a resend"),
+            Ps2Error::UnexpectedResponse(response) => {
+                write!(f, "unexpected response {response:#04x}")
+            }
+            Ps2Error::NotInitialized => write!(f, "controller not initialized"),
+        }
+    }
+}
+
+/// What [`init`] found on each port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Devices {
+    pub first: Result<DeviceType, Ps2Error>,
+    pub second: Result<DeviceType, Ps2Error>,
+}
+
+struct Controller {
+    data: Port<u8>,
+    command: Port<u8>,
+    config: u8,
+    devices: Option<Devices>,
+}
+
+/// Locks the controller with interrupts disabled, as the interrupt handlers use it too.
+fn with_controller<R>(f: impl FnOnce(&mut Controller) -> R) -> R {
+    without_interrupts(|| f(&mut CONTROLLER.lock()))
+}
+
+/// Tests the controller and the devices on its ports, and sets up the keyboard and the mouse.
+/// Interrupts are disabled meanwhile, which can take a while on real hardware.
+///
+/// Only fails if the controller itself does not work. What went wrong with each port is in the
+/// returned [`Devices`].
+pub fn init() -> Result<Devices, Ps2Error> {
+    let devices = with_controller(|controller| controller.init())?;
+    if matches!(devices.second, Ok(device) if device.is_mouse()) {
+        mouse::register_interrupt();
+    }
+    Ok(devices)
+}
+
+/// What [`init`] found, or `None` if it has not run or the controller does not work.
+pub fn devices() -> Option<Devices> {
+    with_controller(|controller| controller.devices)
+}
+
+/// Called from the keyboard and the mouse interrupt. Either may find the other device's byte.
+pub(crate) fn handle_interrupt() {
+    // Already read while a command waited for its answer if there is none
+    if let Some((port, byte)) = with_controller(|controller| controller.try_read()) {
+        pass_on(port, byte);
+    }
+}
+
+/// Hands input to the device's driver.
+fn pass_on(port: Ps2Port, byte: u8) {
+    match port {
+        Ps2Port::First => crate::task::keyboard::add_scancode(byte),
+        Ps2Port::Second => crate::task::mouse::add_byte(byte),
+    }
+}
+
+impl Controller {
+    const fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            command: Port::new(COMMAND_PORT),
+            config: 0,
+            devices: None,
+        }
+    }
+
+    fn init(&mut self) -> Result<Devices, Ps2Error> {
+        self.devices = None;
+        self.send_command(DISABLE_FIRST)?;
+        self.send_command(DISABLE_SECOND)?;
+        self.flush();
+
+        let original = self.read_config()?;
+        let devices = self.set_up(original);
+        if devices.is_err() {
+            // Leave the keyboard working the way the firmware set it up
+            if self.write_config(original).is_ok() {
+                self.config = original;
+            }
+            let _ = self.send_command(ENABLE_FIRST);
+        }
+        devices
+    }
+
+    /// Does the work of [`init`](Self::init), starting from the configuration byte the
+    /// firmware left.
+    fn set_up(&mut self, original: u8) -> Result<Devices, Ps2Error> {
+        // No interrupts or translation while the devices are set up
+        let config = original & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        match self.command_response(SELF_TEST)? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Ps2Error::ControllerTestFailed(response)),
+        }
+        // Some controllers reset themselves during the test
+        self.write_config(config)?;
+
+        // The second port's clock only turns on if there is a second port
+        let mut dual = false;
+        if config & CONFIG_SECOND_CLOCK_DISABLED != 0 {
+            self.send_command(ENABLE_SECOND)?;
+            dual = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.send_command(DISABLE_SECOND)?;
+        }
+
+        let first = self.probe(Ps2Port::First);
+        let second = if dual {
+            self.probe(Ps2Port::Second)
+        } else {
+            Err(Ps2Error::NoDevice(Ps2Port::Second))
+        };
+        self.config = self.read_config()?;
+        let first = match first {
+            Ok(DeviceType::Keyboard) => keyboard::setup(self).map(|()| DeviceType::Keyboard),
+            other => other,
+        };
+        let second = match second {
+            Ok(device) if device.is_mouse() => mouse::setup(self),
+            other => other,
+        };
+
+        let devices = Devices { first, second };
+        self.devices = Some(devices);
+        Ok(devices)
+    }
+
+    /// Tests the port, resets the device on it and asks what it is. Leaves the port enabled, but
+    /// the device does not send anything until it is told to.
+    fn probe(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+        let (test, enable) = match port {
+            Ps2Port::First => (TEST_FIRST, ENABLE_FIRST),
+            Ps2Port::Second => (TEST_SECOND, ENABLE_SECOND),
+        };
+        match self.command_response(test)? {
+            PORT_TEST_PASSED => {}
+            response => return Err(Ps2Error::PortTestFailed(port, response)),
+        }
+        self.send_command(enable)?;
+
+        self.device_command(port, &[DEVICE_RESET])
+            .map_err(|error| match error {
+                Ps2Error::Timeout => Ps2Error::NoDevice(port),
+                error => error,
+            })?;
+        match self.read_from(port, RESET_TIMEOUT)? {
+            DEVICE_RESET_PASSED => {}
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+        // Mice follow up with their ID
+        while self.read_from(port, TIMEOUT).is_ok() {}
+
+        self.device_command(port, &[DEVICE_DISABLE_SCANNING])?;
+        self.identify(port)
+    }
+
+    /// Asks the device on `port` what it is. It must not be scanning.
+    fn identify(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+        self.device_command(port, &[DEVICE_IDENTIFY])?;
+        let mut id = [0; 2];
+        let mut len = 0;
+        while len < id.len() {
+            match self.read_from(port, TIMEOUT) {
+                Ok(byte) => id[len] = byte,
+                Err(Ps2Error::Timeout) => break,
+                Err(error) => return Err(error),
+            }
+            len += 1;
+        }
+        Ok(DeviceType::from_id(&id[..len]))
+    }
+
+    fn device(&self, port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+        let devices = self.devices.ok_or(Ps2Error::NotInitialized)?;
+        match port {
+            Ps2Port::First => devices.first,
+            Ps2Port::Second => devices.second,
+        }
+    }
+
+    fn set_config_bits(&mut self, bits: u8, set: bool) -> Result<(), Ps2Error> {
+        let config = if set {
+            self.config | bits
+        } else {
+            self.config & !bits
+        };
+        self.write_config(config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    /// Throws away whatever the devices sent before the controller was set up.
+    fn flush(&mut self) {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_OUTPUT_FULL == 0 {
+                return;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn wait_for_input_space(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_for_input_space()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    /// Sends a command to the controller that it answers with one byte.
+    fn command_response(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.send_command(command)?;
+        self.read(TIMEOUT).map(|(_, byte)| byte)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_response(READ_CONFIG)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.send_command(WRITE_CONFIG)?;
+        self.write(config)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_for_input_space()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Waits for a byte, and returns it with the port it came from.
+    fn read(&mut self, spins: u32) -> Result<(Ps2Port, u8), Ps2Error> {
+        for _ in 0..spins {
+            if let Some(read) = self.try_read() {
+                return Ok(read);
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn try_read(&mut self) -> Option<(Ps2Port, u8)> {
+        let status = self.status();
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let port = match status & STATUS_SECOND_PORT {
+            0 => Ps2Port::First,
+            _ => Ps2Port::Second,
+        };
+        Some((port, unsafe { self.data.read() }))
+    }
+
+    /// Waits for a byte from the device on `port`. Bytes from the other one are passed on to
+    /// its driver meanwhile.
+    fn read_from(&mut self, port: Ps2Port, spins: u32) -> Result<u8, Ps2Error> {
+        for _ in 0..spins {
+            match self.try_read() {
+                Some((from, byte)) if from == port => return Ok(byte),
+                Some((from, byte)) => pass_on(from, byte),
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Sends the bytes of a command to the device on `port`, waiting for it to acknowledge
+    /// each one.
+    fn device_command(&mut self, port: Ps2Port, bytes: &[u8]) -> Result<(), Ps2Error> {
+        for &byte in bytes {
+            self.device_byte(port, byte)?;
+        }
+        Ok(())
+    }
+
+    fn device_byte(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RETRIES {
+            if port == Ps2Port::Second {
+                self.send_command(WRITE_SECOND)?;
+            }
+            self.write(byte)?;
+            loop {
+                match self.read_from(port, TIMEOUT)? {
+                    DEVICE_ACK => return Ok(()),
+                    DEVICE_RESEND => break,
+                    // Input the device sent before it got the command
+                    input => pass_on(port, input),
+                }
+            }
+        }
+        Err(Ps2Error::Resend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn devices_are_told_apart_by_their_id() {
+        assert_eq!(DeviceType::from_id(&[]), DeviceType::Keyboard);
+        assert_eq!(DeviceType::from_id(&[0xab, 0x83]), DeviceType::Keyboard);
+        assert_eq!(DeviceType::from_id(&[0x03]), DeviceType::WheelMouse);
+        assert_eq!(DeviceType::from_id(&[0x42]), DeviceType::Unknown(0x42));
+        assert!(DeviceType::from_id(&[0x00]).is_mouse());
+    }
+
+    #[test_case]
+    fn controller_finds_the_keyboard() {
+        let devices = devices().expect("PS/2 controller not initialized");
+        assert_eq!(devices.first, Ok(DeviceType::Keyboard));
+    }
+
+    #[test_case]
+    fn controller_finds_the_mouse() {
+        let devices = devices().expect("PS/2 controller not initialized");
+        assert!(devices.second.map_or(false, DeviceType::is_mouse));
+        assert_eq!(mouse::device(), devices.second.ok());
+    }
+}
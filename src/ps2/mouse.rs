@@ -0,0 +1,89 @@
+//! Commands for the mouse on the second port, and its interrupt line.
+
+use super::{
+    with_controller, Controller, DeviceType, Ps2Error, Ps2Port, CONFIG_SECOND_IRQ,
+    DEVICE_ENABLE_SCANNING,
+};
+use crate::interrupts::dynamic::{self, HandlerId};
+use spin::Once;
+
+const MOUSE_IRQ: u8 = 12;
+
+const SET_SAMPLE_RATE: u8 = 0xf3;
+/// Sample rates that turn on the scroll wheel of an IntelliMouse when set in this order.
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// Sample rates that turn on the two extra buttons as well, once the wheel is on.
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+/// Packets per second once the mouse is set up.
+const SAMPLE_RATE: u8 = 100;
+
+static HANDLER: Once<HandlerId> = Once::new();
+
+/// Sets up the mouse [`init`](super::init) found on the second port, and turns on its
+/// interrupts. Returns what the mouse turned out to be once its extensions are on.
+pub(super) fn setup(controller: &mut Controller) -> Result<DeviceType, Ps2Error> {
+    let mut device = controller.unlock(&WHEEL_SEQUENCE)?;
+    if device == DeviceType::WheelMouse {
+        device = controller.unlock(&FIVE_BUTTON_SEQUENCE)?;
+    }
+    controller.device_command(
+        Ps2Port::Second,
+        &[SET_SAMPLE_RATE, SAMPLE_RATE, DEVICE_ENABLE_SCANNING],
+    )?;
+    controller.set_config_bits(CONFIG_SECOND_IRQ, true)?;
+    Ok(device)
+}
+
+/// Handles IRQ12 from now on. Only registers once, however often [`init`](super::init) runs.
+pub(super) fn register_interrupt() {
+    HANDLER.call_once(|| {
+        dynamic::register_closure(dynamic::irq_vector(MOUSE_IRQ), |_, _| {
+            super::handle_interrupt()
+        })
+        .expect("mouse interrupt line is not dynamic")
+    });
+}
+
+impl Controller {
+    fn mouse(&mut self) -> Result<(), Ps2Error> {
+        match self.device(Ps2Port::Second) {
+            Ok(device) if device.is_mouse() => Ok(()),
+            Ok(_) => Err(Ps2Error::NoDevice(Ps2Port::Second)),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Sets the sample rates of an extension's sequence, and asks the mouse what it is now.
+    /// Mice without the extension just keep their ID.
+    fn unlock(&mut self, sequence: &[u8]) -> Result<DeviceType, Ps2Error> {
+        for &rate in sequence {
+            self.device_command(Ps2Port::Second, &[SET_SAMPLE_RATE, rate])?;
+        }
+        self.identify(Ps2Port::Second)
+    }
+}
+
+/// The kind of mouse on the second port, or `None` if there is none.
+pub fn device() -> Option<DeviceType> {
+    with_controller(|controller| controller.device(Ps2Port::Second))
+        .ok()
+        .filter(|device| device.is_mouse())
+}
+
+/// Changes how many packets per second the mouse sends, from 10 to 200.
+pub fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        controller.mouse()?;
+        controller.device_command(Ps2Port::Second, &[SET_SAMPLE_RATE, rate.clamp(10, 200)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn sample_rate_is_set() {
+        assert_eq!(set_sample_rate(SAMPLE_RATE), Ok(()));
+    }
+}
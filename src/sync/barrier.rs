@@ -0,0 +1,68 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::wait_queue::WaitQueue;
+
+/// Blocks a fixed number of threads until all of them have reached it.
+#[derive(Debug)]
+pub struct Barrier {
+    threads: usize,
+    state: Mutex<BarrierState>,
+    waiters: WaitQueue,
+}
+
+#[derive(Debug)]
+struct BarrierState {
+    arrived: usize,
+    /// Incremented whenever all threads have arrived, which lets the barrier be reused.
+    generation: u64,
+}
+
+/// Returned by [`Barrier::wait`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Whether this thread was the last to arrive. Exactly one thread per round is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+impl Barrier {
+    pub const fn new(threads: usize) -> Self {
+        Barrier {
+            threads,
+            state: Mutex::new(BarrierState {
+                arrived: 0,
+                generation: 0,
+            }),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until `threads` threads, this one included, have called `wait`.
+    pub fn wait(&self) -> BarrierWaitResult {
+        let generation = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.arrived += 1;
+            if state.arrived < self.threads {
+                return Some(state.generation);
+            }
+            state.arrived = 0;
+            state.generation += 1;
+            self.waiters.wake_all();
+            None
+        });
+        match generation {
+            Some(generation) => {
+                self.waiters
+                    .wait_while(|| self.state.lock().generation == generation);
+                BarrierWaitResult { leader: false }
+            }
+            None => BarrierWaitResult { leader: true },
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::MutexGuard;
+use crate::task::wait_queue::WaitQueue;
+
+/// Lets threads wait for a condition on the data behind a [`Mutex`](super::Mutex) to become
+/// true.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    /// Counts notifications, so a waiter can tell whether one happened after it unlocked.
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex and blocks until notified, then locks it again.
+    ///
+    /// Like any condition variable, this may return without the condition being true, so it
+    /// should be checked in a loop, or with [`wait_while`](Self::wait_while).
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = MutexGuard::mutex(&guard);
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_while(|| self.generation.load(Ordering::Acquire) == generation);
+        mutex.lock()
+    }
+
+    /// Blocks for as long as `condition` returns `true` for the data behind the mutex.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes one waiting thread. Returns `false` if there was none.
+    pub fn notify_one(&self) -> bool {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one()
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all()
+    }
+}
@@ -0,0 +1,23 @@
+//! Locks that put waiting threads to sleep instead of spinning.
+//!
+//! A thread holding a `spin::Mutex` can be preempted, leaving everyone else who wants the lock
+//! spinning for the rest of their time slice. These types block waiting threads on a
+//! [`WaitQueue`](crate::task::wait_queue::WaitQueue) instead, and hand the lock straight to the
+//! thread that has waited the longest when it is released, so it cannot be taken in between.
+//!
+//! They must not be used from interrupt handlers, which cannot block. Those, and everything
+//! they share state with, use a [`SpinLock`], which the lock validator can check.
+
+mod barrier;
+mod condvar;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spin_lock;
+
+pub use barrier::{Barrier, BarrierWaitResult};
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::{SpinLock, SpinLockGuard};
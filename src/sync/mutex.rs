@@ -0,0 +1,125 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::task::scheduler::current_thread;
+use crate::task::wait_queue::WaitQueue;
+
+const UNLOCKED: u64 = u64::MAX;
+
+/// A mutual exclusion lock that blocks the threads waiting for it.
+pub struct Mutex<T: ?Sized> {
+    /// The thread holding the lock, or [`UNLOCKED`].
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            owner: AtomicU64::new(UNLOCKED),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Blocks until the lock is free, or until the previous holder hands it over.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let tid = current_thread().as_u64();
+        assert_ne!(
+            self.owner.load(Ordering::Acquire),
+            tid,
+            "Mutex locked twice by thread {tid}"
+        );
+        self.waiters.wait_while(|| {
+            match self
+                .owner
+                .compare_exchange(UNLOCKED, tid, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => false,
+                Err(owner) => owner != tid,
+            }
+        });
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let tid = current_thread().as_u64();
+        self.owner
+            .compare_exchange(UNLOCKED, tid, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        let handed_off = self.waiters.wake_one_with(|tid| {
+            self.owner.store(tid.as_u64(), Ordering::Release);
+        });
+        if !handed_off {
+            self.owner.store(UNLOCKED, Ordering::Release);
+        }
+    }
+}
+
+impl<T: ?Sized + Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> core::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Mutex")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The lock the guard belongs to, for [`Condvar`](super::Condvar) to lock it again.
+    pub(super) fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,201 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::scheduler::current_thread;
+use crate::task::thread::ThreadId;
+use crate::task::wait_queue::WaitQueue;
+
+/// A lock that allows either many readers or a single writer, blocking the threads waiting for
+/// it.
+///
+/// Readers and writers take turns: new readers wait while a writer is waiting, and when a
+/// writer is done every waiting reader gets the lock before the next writer.
+pub struct RwLock<T: ?Sized> {
+    state: Mutex<RwLockState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+#[derive(Debug)]
+struct RwLockState {
+    readers: usize,
+    writer: Option<ThreadId>,
+    /// Woken readers that were handed the lock, and are already counted in `readers`.
+    handed_to: Vec<ThreadId>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: Mutex::new(RwLockState {
+                readers: 0,
+                writer: None,
+                handed_to: Vec::new(),
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Blocks until there is no writer holding or waiting for the lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let tid = current_thread();
+        self.readers.wait_while(|| {
+            let mut state = self.state.lock();
+            if let Some(index) = state.handed_to.iter().position(|&t| t == tid) {
+                state.handed_to.swap_remove(index);
+                false
+            } else if state.writer.is_none() && !self.writers.has_waiters() {
+                state.readers += 1;
+                false
+            } else {
+                true
+            }
+        });
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Blocks until no other thread holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let tid = current_thread();
+        self.writers.wait_while(|| {
+            let mut state = self.state.lock();
+            if state.writer == Some(tid) {
+                // Handed over by the previous holder
+                false
+            } else if state.writer.is_none() && state.readers == 0 {
+                state.writer = Some(tid);
+                false
+            } else {
+                true
+            }
+        });
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.writer.is_some() || self.writers.has_waiters() {
+                return None;
+            }
+            state.readers += 1;
+            Some(RwLockReadGuard { lock: self })
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.writer.is_some() || state.readers > 0 {
+                return None;
+            }
+            state.writer = Some(current_thread());
+            Some(RwLockWriteGuard { lock: self })
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn read_unlock(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.readers -= 1;
+            if state.readers == 0 {
+                self.writers.wake_one_with(|tid| state.writer = Some(tid));
+            }
+        });
+    }
+
+    fn write_unlock(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.writer = None;
+            let state = &mut *state;
+            let readers = self.readers.wake_all_with(|tid| {
+                state.readers += 1;
+                state.handed_to.push(tid);
+            });
+            if readers == 0 {
+                self.writers.wake_one_with(|tid| state.writer = Some(tid));
+            }
+        });
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> core::fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = without_interrupts(|| {
+            let state = self.state.lock();
+            (state.readers, state.writer)
+        });
+        f.debug_struct("RwLock")
+            .field("readers", &state.0)
+            .field("writer", &state.1)
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
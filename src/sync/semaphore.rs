@@ -0,0 +1,79 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::scheduler::current_thread;
+use crate::task::thread::ThreadId;
+use crate::task::wait_queue::WaitQueue;
+
+/// A counter of permits. Taking one blocks while there are none left.
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    state: Mutex<SemaphoreState>,
+    waiters: WaitQueue,
+}
+
+#[derive(Debug, Default)]
+struct SemaphoreState {
+    permits: usize,
+    /// Woken threads that were handed a released permit.
+    handed_to: Vec<ThreadId>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(SemaphoreState {
+                permits,
+                handed_to: Vec::new(),
+            }),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit, blocking until one is released if there are none.
+    pub fn acquire(&self) {
+        let tid = current_thread();
+        self.waiters.wait_while(|| {
+            let mut state = self.state.lock();
+            if let Some(index) = state.handed_to.iter().position(|&t| t == tid) {
+                state.handed_to.swap_remove(index);
+                false
+            } else if state.permits > 0 {
+                state.permits -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.permits > 0 {
+                state.permits -= 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Gives a permit back, directly to the longest waiting thread if there is one.
+    pub fn release(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let handed_off = self.waiters.wake_one_with(|tid| state.handed_to.push(tid));
+            if !handed_off {
+                state.permits += 1;
+            }
+        });
+    }
+
+    /// The number of permits that can be taken without blocking.
+    pub fn available_permits(&self) -> usize {
+        without_interrupts(|| self.state.lock().permits)
+    }
+}
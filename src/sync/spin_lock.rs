@@ -0,0 +1,89 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicUsize;
+
+use crate::debug::lockdep;
+
+/// A `spin::Mutex` with a name, which the lock validator checks in debug builds.
+///
+/// Locks with the same name share a class, so the order they are taken in is checked for all of
+/// them together.
+pub struct SpinLock<T: ?Sized> {
+    name: &'static str,
+    class: AtomicUsize,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
+    address: usize,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        SpinLock {
+            name,
+            class: AtomicUsize::new(lockdep::UNREGISTERED),
+            inner: spin::Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let address = self.address();
+        lockdep::before_lock(address, &self.class, self.name);
+        SpinLockGuard {
+            address,
+            guard: self.inner.lock(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        let address = self.address();
+        lockdep::after_try_lock(address);
+        Some(SpinLockGuard { address, guard })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpinLock")
+            .field("name", &self.name)
+            .field("inner", &&self.inner)
+            .finish()
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::unlocked(self.address);
+    }
+}
@@ -0,0 +1,39 @@
+use core::arch::asm;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::task::scheduler;
+use crate::task::thread::Registers;
+use crate::{println, serial_println};
+
+/// Gives the rest of the time slice to the next runnable thread.
+pub const SYS_YIELD: u64 = 24;
+/// Ends the calling thread with the exit code in `rdi`.
+pub const SYS_EXIT: u64 = 60;
+
+/// Machine code user threads return into, which exits with code 0:
+/// `mov eax, 60; xor edi, edi; int 0x80; ud2`.
+pub const USER_EXIT_STUB: &[u8] = b"\xB8\x3C\x00\x00\x00\x31\xFF\xCD\x80\x0F\x0B";
+
+/// Ends the current thread through the same path user threads take.
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        asm!(
+            "int 0x80",
+            in("rax") SYS_EXIT,
+            in("rdi") code as i64,
+            options(noreturn)
+        );
+    }
+}
+
+pub(crate) fn handle(_stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    match regs.rax {
+        SYS_YIELD => scheduler::yield_now(),
+        SYS_EXIT => scheduler::exit(regs.rdi as i32),
+        _ => {
+            serial_println!("syscall!");
+            println!("syscall!");
+            println!("User rax: {}", regs.rax);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Waiting across the line between async tasks and preemptive threads.
+//!
+//! Tasks must not block, as that stalls every other task on their executor. Work that takes
+//! long, e.g. waiting for a disk, goes to a thread with [`spawn_blocking`] instead. The other
+//! way round, a thread runs a future to completion with [`block_on`].
+
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use super::scheduler;
+use super::sync::oneshot;
+use super::wait_queue::WaitQueue;
+
+/// Runs `f` on a new kernel thread. The returned future completes with its result.
+///
+/// The thread is preempted like any other, so the caller's executor keeps running its other
+/// tasks meanwhile. Dropping the future does not stop the thread, only discards the result.
+pub fn spawn_blocking<F, T>(f: F) -> BlockingHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    scheduler::Builder::new().name("blocking").spawn(move || {
+        // The receiver may have been dropped, then nobody wants the result
+        let _ = sender.send(f());
+    });
+    BlockingHandle { receiver }
+}
+
+/// The future returned by [`spawn_blocking`].
+pub struct BlockingHandle<T> {
+    receiver: oneshot::Receiver<T>,
+}
+
+impl<T> Future for BlockingHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|result| result.expect("blocking thread ended without a result"))
+    }
+}
+
+/// Runs `future` on the current thread until it completes, blocking the thread while the
+/// future waits.
+///
+/// Must not be called from inside a task, as that blocks its whole executor.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    futures_util::pin_mut!(future);
+    let thread = Arc::new(ThreadWaker {
+        woken: AtomicBool::new(false),
+        queue: WaitQueue::new(),
+    });
+    let waker = Waker::from(thread.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        thread.woken.store(false, Ordering::SeqCst);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread
+            .queue
+            .wait_while(|| !thread.woken.load(Ordering::SeqCst));
+    }
+}
+
+/// Wakes the thread running [`block_on`]. Safe to use from interrupt handlers.
+struct ThreadWaker {
+    /// Set when the future was woken since it was last polled.
+    woken: AtomicBool,
+    queue: WaitQueue,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.queue.wake_one();
+    }
+}
@@ -0,0 +1,117 @@
+use core::arch::asm;
+use core::mem::size_of;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::VirtAddr;
+
+use super::thread::Registers;
+use crate::gdt::GDT;
+
+/// What [`switch_context`] leaves on the stack of the thread it switches away from, from the
+/// lowest address up.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SwitchFrame {
+    pub rflags: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    /// Where `switch_context` returns to.
+    pub rip: u64,
+}
+
+impl SwitchFrame {
+    /// A frame that starts a new kernel thread by calling `entrypoint(argument)`.
+    pub fn kernel_entry(entrypoint: u64, argument: u64) -> Self {
+        SwitchFrame {
+            rip: kernel_thread_start as u64,
+            r12: entrypoint,
+            r13: argument,
+            ..SwitchFrame::new()
+        }
+    }
+
+    /// A frame that enters user mode with `cr3` through the interrupt frame right above it.
+    pub fn user_entry(cr3: u64) -> Self {
+        SwitchFrame {
+            rip: user_thread_start as u64,
+            r12: cr3,
+            ..SwitchFrame::new()
+        }
+    }
+
+    fn new() -> Self {
+        SwitchFrame {
+            // New threads start with interrupts disabled, like the thread switching to them
+            rflags: 0x2,
+            ..Default::default()
+        }
+    }
+
+    /// The registers of a thread paused in `switch_context`, in the form the debugger expects.
+    /// `stack_pointer` is where the frame is stored, and `cr3` the page table the thread uses.
+    pub fn to_interrupt_context(
+        &self,
+        stack_pointer: u64,
+        cr3: u64,
+    ) -> (InterruptStackFrameValue, Registers) {
+        let stack_frame = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(self.rip),
+            code_segment: GDT.1.kernel_code_selector.0 as u64,
+            cpu_flags: self.rflags,
+            stack_pointer: VirtAddr::new(stack_pointer + size_of::<SwitchFrame>() as u64),
+            stack_segment: GDT.1.kernel_data_selector.0 as u64,
+        };
+        let regs = Registers {
+            cr3,
+            rbx: self.rbx,
+            rbp: self.rbp,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            ..Default::default()
+        };
+        (stack_frame, regs)
+    }
+}
+
+/// Saves the callee-saved registers of the current thread on its stack, stores its stack
+/// pointer in `current` and resumes the thread whose stack pointer is `next`.
+///
+/// Returns once another thread switches back to this one.
+#[naked]
+pub unsafe extern "C" fn switch_context(current: *mut u64, next: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    )
+}
+
+#[naked]
+unsafe extern "C" fn kernel_thread_start() -> ! {
+    asm!("mov rdi, r13", "call r12", "ud2", options(noreturn))
+}
+
+#[naked]
+unsafe extern "C" fn user_thread_start() -> ! {
+    asm!("mov cr3, r12", "iretq", options(noreturn))
+}
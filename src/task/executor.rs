@@ -1,92 +1,467 @@
-use super::{Task, TaskId};
+use super::scheduler;
+use super::wait_queue::WaitQueue;
+use super::{Task, TaskId, TaskPriority};
+use crate::sync::SpinLock;
+use crate::time;
+use alloc::string::String;
 use alloc::task::Wake;
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::vec::Vec;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
 
+/// How many tasks the executor polls before it lets other threads run.
+const POLL_BUDGET: usize = 64;
+
+/// How many times ready tasks of a priority are passed over for higher ones before one of them
+/// is polled anyway.
+const STARVATION_LIMIT: u32 = 8;
+
+/// Runs async tasks on the thread that calls [`run`](Executor::run). Several executors can run
+/// on different threads, see [`spawn_thread`].
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    tasks: BTreeMap<TaskId, ExecutorTask>,
+    shared: Arc<Shared>,
+}
+
+/// The part of an executor that [`Spawner`]s and wakers use.
+struct Shared {
+    /// Tasks that were woken and wait to be polled. Always has room for every task, so waking a
+    /// task, which interrupt handlers do, never allocates.
+    ready: SpinLock<ReadyQueues>,
+    /// Tasks spawned since the executor last looked, which it has not taken over yet.
+    spawned: SpinLock<Vec<ExecutorTask>>,
+    /// The number of tasks that have not finished yet.
+    tasks: AtomicUsize,
+    /// Where the thread running the executor blocks while no task is ready.
+    idle: WaitQueue,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+    wakeups: AtomicU64,
+    /// How often the executor used up its budget and let other threads run.
+    yields: AtomicU64,
+}
+
+/// One queue of ready tasks per priority.
+struct ReadyQueues {
+    queues: [VecDeque<TaskId>; TaskPriority::LEVELS],
+    /// How often each priority was passed over since one of its tasks was last polled.
+    passed_over: [u32; TaskPriority::LEVELS],
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        ReadyQueues {
+            queues: Default::default(),
+            passed_over: [0; TaskPriority::LEVELS],
+        }
+    }
+
+    fn push(&mut self, priority: TaskPriority, task_id: TaskId) {
+        self.queues[priority.level()].push_back(task_id);
+    }
+
+    /// Takes a task of the highest priority, unless a lower one has been passed over too often.
+    fn pop(&mut self) -> Option<TaskId> {
+        let waiting = |level: &usize| !self.queues[*level].is_empty();
+        let highest = (0..TaskPriority::LEVELS).rev().find(waiting)?;
+        let level = (0..highest)
+            .filter(waiting)
+            .find(|&level| self.passed_over[level] >= STARVATION_LIMIT)
+            .unwrap_or(highest);
+        for lower in (0..highest).filter(waiting) {
+            self.passed_over[lower] += 1;
+        }
+        self.passed_over[level] = 0;
+        self.queues[level].pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
+
+struct ExecutorTask {
+    task: Task,
+    state: Arc<TaskWaker>,
+    waker: Waker,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            shared: Arc::new(Shared {
+                ready: SpinLock::new("executor queue", ReadyQueues::new()),
+                spawned: SpinLock::new("executor spawned tasks", Vec::new()),
+                tasks: AtomicUsize::new(0),
+                idle: WaitQueue::new(),
+                polls: AtomicU64::new(0),
+                poll_cycles: AtomicU64::new(0),
+                wakeups: AtomicU64::new(0),
+                yields: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// A handle that spawns tasks onto this executor, also from inside its own tasks.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
         }
     }
 
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawner().spawn(future)
+    }
+
+    pub fn spawn_with_priority<F>(&self, priority: TaskPriority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawner().spawn_with_priority(priority, future)
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
-            self.run_ready_tasks();
-            self.sleep_if_idle();
+            if self.run_ready_tasks() {
+                self.yield_thread();
+            } else {
+                self.sleep_if_idle();
+            }
         }
     }
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
+    /// Polls tasks until none is ready, including those woken meanwhile. Returns once all
+    /// remaining tasks wait for something.
+    pub fn run_until_stalled(&mut self) {
+        while self.run_ready_tasks() {
+            self.yield_thread();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+    /// The number of tasks that have not finished yet.
+    pub fn task_count(&self) -> usize {
+        self.shared.tasks.load(Ordering::SeqCst)
+    }
+
+    /// Totals over every task this executor has polled.
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
+    /// A snapshot of the tasks this executor has taken over and that have not finished yet.
+    pub fn task_stats(&self) -> Vec<TaskStats> {
+        self.tasks.values().map(|task| task.state.stats()).collect()
+    }
+
+    /// Lets other threads run after the executor used up its budget.
+    fn yield_thread(&self) {
+        self.shared.yields.fetch_add(1, Ordering::SeqCst);
+        scheduler::yield_now();
+    }
+
+    /// Blocks the thread, letting other threads run, until a task is woken.
+    fn sleep_if_idle(&self) {
+        self.shared
+            .idle
+            .wait_while(|| self.shared.ready.lock().is_empty());
+    }
+
+    /// Polls ready tasks until none is left or the budget is used up. Returns whether tasks are
+    /// still ready.
+    fn run_ready_tasks(&mut self) -> bool {
+        for _ in 0..POLL_BUDGET {
+            let task_id = match self.shared.pop_ready() {
+                Some(task_id) => task_id,
+                None => return false,
+            };
+            if !self.tasks.contains_key(&task_id) {
+                self.take_spawned();
+            }
+            // Finished tasks can still be woken by wakers that outlive them
+            let task = match self.tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue,
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+            if task.state.cancelled.load(Ordering::SeqCst) {
+                self.finish(task_id);
+                continue;
+            }
+            task.state.queued.store(false, Ordering::SeqCst);
+            let mut context = Context::from_waker(&task.waker);
+            let start = time::tsc();
+            let poll = task.task.poll(&mut context);
+            let cycles = time::tsc() - start;
+            task.state.polls.fetch_add(1, Ordering::SeqCst);
+            task.state.poll_cycles.fetch_add(cycles, Ordering::SeqCst);
+            self.shared.polls.fetch_add(1, Ordering::SeqCst);
+            self.shared.poll_cycles.fetch_add(cycles, Ordering::SeqCst);
+            if poll.is_ready() {
+                self.finish(task_id);
+            }
+        }
+        without_interrupts(|| !self.shared.ready.lock().is_empty())
+    }
+
+    fn take_spawned(&mut self) {
+        let spawned = core::mem::take(&mut *self.shared.spawned.lock());
+        for task in spawned {
+            if self.tasks.insert(task.task.id, task).is_some() {
+                panic!("Task with same ID already in tasks");
             }
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// Drops the task, which cancels it if it has not completed.
+    fn finish(&mut self, task_id: TaskId) {
+        if let Some(task) = self.tasks.remove(&task_id) {
+            // Keeps wakers that outlive the task from queueing it again
+            task.state.queued.store(true, Ordering::SeqCst);
+            self.shared.tasks.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Starts a kernel thread running a new executor, and returns a spawner for it. The thread is
+/// blocked whenever none of its tasks is ready, and never exits.
+pub fn spawn_thread(name: impl Into<String>) -> Spawner {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    scheduler::Builder::new()
+        .name(name)
+        .spawn(move || executor.run());
+    spawner
+}
+
+impl Shared {
+    fn stats(&self) -> ExecutorStats {
+        ExecutorStats {
+            tasks: self.tasks.load(Ordering::SeqCst),
+            polls: self.polls.load(Ordering::SeqCst),
+            poll_cycles: self.poll_cycles.load(Ordering::SeqCst),
+            wakeups: self.wakeups.load(Ordering::SeqCst),
+            yields: self.yields.load(Ordering::SeqCst),
+        }
+    }
+
+    fn pop_ready(&self) -> Option<TaskId> {
+        without_interrupts(|| self.ready.lock().pop())
+    }
+}
+
+/// Spawns tasks onto an [`Executor`]. It can be cloned and sent to other tasks and threads, but
+/// not used from interrupt handlers, as spawning allocates.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    /// Queues `future` to run on the executor. Its output can be awaited through the returned
+    /// handle, and dropping the handle lets the task run on unobserved.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_priority(TaskPriority::default(), future)
+    }
+
+    /// Like [`spawn`](Self::spawn), but the task is polled with the given priority.
+    pub fn spawn_with_priority<F>(&self, priority: TaskPriority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let output = Arc::new(SpinLock::new("join handle", JoinState::new()));
+        let guard = CompletionGuard {
+            output: Some(output.clone()),
+        };
+        let task = Task::with_priority(
+            async move {
+                let value = future.await;
+                guard.complete(value);
+            },
+            priority,
+        );
+        let state = Arc::new(TaskWaker {
+            task_id: task.id,
+            priority,
+            queued: AtomicBool::new(true),
+            cancelled: AtomicBool::new(false),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+            executor: self.shared.clone(),
+        });
+        let waker = Waker::from(state.clone());
+
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("Task with same ID already in tasks");
+        self.shared.spawned.lock().push(ExecutorTask {
+            task,
+            state: state.clone(),
+            waker,
+        });
+        let tasks = self.shared.tasks.fetch_add(1, Ordering::SeqCst) + 1;
+        without_interrupts(|| {
+            let mut ready = self.shared.ready.lock();
+            // Room for every task on top of the entries already there, which may belong to
+            // tasks that have finished since
+            ready.queues[priority.level()].reserve(tasks);
+            ready.push(priority, task_id);
+        });
+        self.shared.idle.wake_one();
+
+        JoinHandle {
+            output,
+            task: state,
+        }
+    }
+
+    /// Totals over every task the executor has polled, see [`Executor::stats`].
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
+}
+
+/// The task ended without producing an output, as it was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+/// Awaits the output of a task spawned on an [`Executor`], or cancels it.
+///
+/// It must not be polled again once it has returned the output.
+pub struct JoinHandle<T> {
+    output: Arc<SpinLock<JoinState<T>>>,
+    task: Arc<TaskWaker>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task the next time the executor gets to it, dropping its future. Does nothing
+    /// if the task has already completed.
+    pub fn cancel(&self) {
+        self.task.cancelled.store(true, Ordering::SeqCst);
+        self.task.wake_task();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.output.lock().finished
+    }
+
+    pub fn stats(&self) -> TaskStats {
+        self.task.stats()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.output.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct JoinState<T> {
+    output: Option<Result<T, Cancelled>>,
+    finished: bool,
+    /// The task awaiting the [`JoinHandle`].
+    waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn new() -> Self {
+        JoinState {
+            output: None,
+            finished: false,
+            waker: None,
+        }
+    }
+}
+
+/// Moves a task's output into its [`JoinHandle`], or reports it cancelled if the task is dropped
+/// before it completed.
+struct CompletionGuard<T> {
+    output: Option<Arc<SpinLock<JoinState<T>>>>,
+}
+
+impl<T> CompletionGuard<T> {
+    fn complete(mut self, value: T) {
+        self.set(Ok(value));
+    }
+
+    fn set(&mut self, output: Result<T, Cancelled>) {
+        if let Some(state) = self.output.take() {
+            let waker = {
+                let mut state = state.lock();
+                state.output = Some(output);
+                state.finished = true;
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
         }
-        self.task_queue.push(task_id).expect("Task queue full");
+    }
+}
+
+impl<T> Drop for CompletionGuard<T> {
+    fn drop(&mut self) {
+        self.set(Err(Cancelled));
     }
 }
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: TaskPriority,
+    /// Set while the task is in the ready queue, so it is queued at most once.
+    queued: AtomicBool,
+    cancelled: AtomicBool,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+    wakeups: AtomicU64,
+    executor: Arc<Shared>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn wake_task(&self) {
+        self.wakeups.fetch_add(1, Ordering::SeqCst);
+        self.executor.wakeups.fetch_add(1, Ordering::SeqCst);
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        without_interrupts(|| self.executor.ready.lock().push(self.priority, self.task_id));
+        self.executor.idle.wake_one();
     }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("Task_queue full");
+    fn stats(&self) -> TaskStats {
+        TaskStats {
+            id: self.task_id.0,
+            priority: self.priority,
+            polls: self.polls.load(Ordering::SeqCst),
+            poll_cycles: self.poll_cycles.load(Ordering::SeqCst),
+            wakeups: self.wakeups.load(Ordering::SeqCst),
+        }
     }
 }
 
@@ -99,3 +474,93 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+/// What an executor reports about one of its tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub id: u64,
+    pub priority: TaskPriority,
+    pub polls: u64,
+    /// Time spent polling the task, in time stamp counter cycles.
+    pub poll_cycles: u64,
+    /// How often the task was woken, including wake-ups while it was already queued.
+    pub wakeups: u64,
+}
+
+impl TaskStats {
+    /// Column titles matching the `Display` output.
+    pub const HEADER: &'static str = "   ID PRIO       POLLS    POLL TIME    WAKEUPS";
+
+    pub fn poll_time(&self) -> Duration {
+        time::tsc_to_duration(self.poll_cycles)
+    }
+}
+
+impl fmt::Display for TaskStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let priority = match self.priority {
+            TaskPriority::Low => "low",
+            TaskPriority::Normal => "normal",
+            TaskPriority::High => "high",
+        };
+        let poll_time = self.poll_time();
+        write!(
+            f,
+            "{:>5} {:<6} {:>10} {:>8}.{:03}s {:>10}",
+            self.id,
+            priority,
+            self.polls,
+            poll_time.as_secs(),
+            poll_time.subsec_millis(),
+            self.wakeups,
+        )
+    }
+}
+
+/// Totals over every task an executor has polled, including those that have finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// Tasks that have not finished yet.
+    pub tasks: usize,
+    pub polls: u64,
+    /// Time spent polling tasks, in time stamp counter cycles.
+    pub poll_cycles: u64,
+    pub wakeups: u64,
+    /// How often the executor used up its budget and let other threads run.
+    pub yields: u64,
+}
+
+impl ExecutorStats {
+    pub fn poll_time(&self) -> Duration {
+        time::tsc_to_duration(self.poll_cycles)
+    }
+}
+
+impl fmt::Display for ExecutorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tasks, {} polls taking {:?}, {} wakeups, {} yields",
+            self.tasks,
+            self.polls,
+            self.poll_time(),
+            self.wakeups,
+            self.yields
+        )
+    }
+}
+
+/// Lets the other ready tasks run before continuing. Tasks that work for long without waiting
+/// should call this now and then.
+pub async fn yield_now() {
+    let mut yielded = false;
+    core::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
@@ -0,0 +1,61 @@
+//! The German 105-key layout, which pc-keyboard 0.5 does not have. Keys that type the same as on
+//! a US keyboard are left to [`Us104Key`].
+
+use pc_keyboard::layouts::Us104Key;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let key = |normal, shifted, alt_gr| symbol(modifiers, normal, shifted, alt_gr);
+        match keycode {
+            KeyCode::BackTick => key('^', '°', None),
+            KeyCode::Key2 => key('2', '"', Some('²')),
+            KeyCode::Key3 => key('3', '§', Some('³')),
+            KeyCode::Key6 => key('6', '&', None),
+            KeyCode::Key7 => key('7', '/', Some('{')),
+            KeyCode::Key8 => key('8', '(', Some('[')),
+            KeyCode::Key9 => key('9', ')', Some(']')),
+            KeyCode::Key0 => key('0', '=', Some('}')),
+            KeyCode::Minus => key('ß', '?', Some('\\')),
+            KeyCode::Equals => key('´', '`', None),
+            KeyCode::BracketSquareLeft => letter(modifiers, 'ü', 'Ü'),
+            KeyCode::BracketSquareRight => key('+', '*', Some('~')),
+            KeyCode::SemiColon => letter(modifiers, 'ö', 'Ö'),
+            KeyCode::Quote => letter(modifiers, 'ä', 'Ä'),
+            KeyCode::HashTilde => key('#', '\'', None),
+            // The key between the left Shift and Y
+            KeyCode::BackSlash => key('<', '>', Some('|')),
+            KeyCode::Comma => key(',', ';', None),
+            KeyCode::Fullstop => key('.', ':', None),
+            KeyCode::Slash => key('-', '_', None),
+            KeyCode::Q if modifiers.alt_gr => DecodedKey::Unicode('@'),
+            KeyCode::E if modifiers.alt_gr => DecodedKey::Unicode('€'),
+            KeyCode::M if modifiers.alt_gr => DecodedKey::Unicode('µ'),
+            // Swapped, Ctrl included
+            KeyCode::Y => Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            keycode => Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// A key that types `shifted` with Shift, and `alt_gr` with AltGr if it has a third symbol.
+fn symbol(modifiers: &Modifiers, normal: char, shifted: char, alt_gr: Option<char>) -> DecodedKey {
+    let c = match alt_gr {
+        Some(alt_gr) if modifiers.alt_gr => alt_gr,
+        _ if modifiers.is_shifted() => shifted,
+        _ => normal,
+    };
+    DecodedKey::Unicode(c)
+}
+
+/// A letter, which Caps Lock makes upper case too.
+fn letter(modifiers: &Modifiers, lower: char, upper: char) -> DecodedKey {
+    DecodedKey::Unicode(if modifiers.is_caps() { upper } else { lower })
+}
@@ -0,0 +1,383 @@
+//! Turns scancodes into key events, keeping track of the modifier keys and the layout.
+
+use super::de105::De105Key;
+use crate::ps2::keyboard::ScancodeSet;
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{
+    layouts, DecodedKey, Error, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout,
+    Modifiers as LayoutModifiers, ScancodeSet1, ScancodeSet2,
+};
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us104 as u8);
+
+/// The keyboard layouts keys can be decoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Dvorak,
+        Layout::Azerty,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    /// The layout called `name`, as returned by [`name`](Self::name).
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Us104
+    }
+}
+
+/// Switches the layout keys are decoded with, from the next key on.
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::SeqCst);
+}
+
+pub fn layout() -> Layout {
+    Layout::ALL[LAYOUT.load(Ordering::SeqCst) as usize]
+}
+
+/// Maps keys with whatever layout [`set_layout`] picked last.
+struct CurrentLayout;
+
+impl KeyboardLayout for CurrentLayout {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &LayoutModifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        match layout() {
+            Layout::Us104 => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk105 => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De105 => De105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// Which modifiers were in effect when a key event happened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// A key going down or up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The key's position, which does not depend on the layout.
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    /// The character the key types with the current layout and modifiers, if it is pressed and
+    /// types one.
+    pub char: Option<char>,
+}
+
+impl KeyEvent {
+    /// Whether the key went down, which it keeps doing while it is held.
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    /// Whether the key is Shift, Ctrl, Alt or one of the lock keys.
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.code,
+            KeyCode::ShiftLeft
+                | KeyCode::ShiftRight
+                | KeyCode::ControlLeft
+                | KeyCode::ControlRight
+                | KeyCode::AltLeft
+                | KeyCode::AltRight
+                | KeyCode::CapsLock
+                | KeyCode::NumpadLock
+                | KeyCode::ScrollLock
+        )
+    }
+
+    /// Whether this is Ctrl together with the key typing `c`, e.g. for Ctrl+C.
+    pub fn is_ctrl(&self, c: char) -> bool {
+        self.is_press()
+            && self.modifiers.ctrl
+            && self.char.map(|ch| ch.to_ascii_lowercase()) == Some(c)
+    }
+}
+
+/// Decodes the scancodes of one set into key events.
+pub struct Decoder {
+    keyboard: Scancodes,
+    /// Whether the left and the right key of each modifier are held.
+    shift: [bool; 2],
+    ctrl: [bool; 2],
+    alt: [bool; 2],
+    caps_lock: Toggle,
+    num_lock: Toggle,
+    scroll_lock: Toggle,
+}
+
+enum Scancodes {
+    Set1(Keyboard<CurrentLayout, ScancodeSet1>),
+    Set2(Keyboard<CurrentLayout, ScancodeSet2>),
+}
+
+impl Scancodes {
+    fn add_byte(&mut self, byte: u8) -> Result<Option<pc_keyboard::KeyEvent>, Error> {
+        match self {
+            Scancodes::Set1(keyboard) => keyboard.add_byte(byte),
+            Scancodes::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: pc_keyboard::KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Scancodes::Set1(keyboard) => keyboard.process_keyevent(event),
+            Scancodes::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+/// A lock key. It only toggles when it goes down, not when the keyboard repeats it.
+#[derive(Debug, Clone, Copy)]
+struct Toggle {
+    on: bool,
+    held: bool,
+}
+
+impl Toggle {
+    const fn new(on: bool) -> Self {
+        Toggle { on, held: false }
+    }
+
+    /// Returns whether the key was repeated.
+    fn key(&mut self, down: bool) -> bool {
+        let repeat = down && self.held;
+        if down && !self.held {
+            self.on = !self.on;
+        }
+        self.held = down;
+        repeat
+    }
+}
+
+impl Decoder {
+    /// A decoder for scancode set 1, which the keyboard controller translates to by default.
+    pub fn new() -> Self {
+        Self::with_scancode_set(ScancodeSet::Set1)
+    }
+
+    pub fn with_scancode_set(set: ScancodeSet) -> Self {
+        // Ctrl is reported in the modifiers, so Ctrl+C still types 'c'
+        let keyboard = match set {
+            ScancodeSet::Set1 => Scancodes::Set1(Keyboard::new(
+                CurrentLayout,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            )),
+            ScancodeSet::Set2 => Scancodes::Set2(Keyboard::new(
+                CurrentLayout,
+                ScancodeSet2,
+                HandleControl::Ignore,
+            )),
+        };
+        Decoder {
+            keyboard,
+            shift: [false; 2],
+            ctrl: [false; 2],
+            alt: [false; 2],
+            caps_lock: Toggle::new(false),
+            // Matches the keyboard below, which starts out with Num Lock on
+            num_lock: Toggle::new(true),
+            scroll_lock: Toggle::new(false),
+        }
+    }
+
+    pub fn scancode_set(&self) -> ScancodeSet {
+        match self.keyboard {
+            Scancodes::Set1(_) => ScancodeSet::Set1,
+            Scancodes::Set2(_) => ScancodeSet::Set2,
+        }
+    }
+
+    /// Feeds the next byte from the keyboard. Returns an event once a key's scancode is complete.
+    /// Bytes that make no sense are skipped.
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        let event = self.keyboard.add_byte(byte).ok()??;
+        let (code, state) = (event.code, event.state);
+        // The keyboard below would toggle the locks again on every repeat
+        let repeat = self.track_modifiers(code, state == KeyState::Down);
+        let decoded = if repeat {
+            None
+        } else {
+            self.keyboard.process_keyevent(event)
+        };
+        let char = match decoded {
+            Some(DecodedKey::Unicode(c)) => Some(c),
+            _ => None,
+        };
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers(),
+            char,
+        })
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.shift.contains(&true),
+            ctrl: self.ctrl.contains(&true),
+            alt: self.alt.contains(&true),
+            caps_lock: self.caps_lock.on,
+            num_lock: self.num_lock.on,
+            scroll_lock: self.scroll_lock.on,
+        }
+    }
+
+    /// Returns whether a lock key was repeated.
+    fn track_modifiers(&mut self, code: KeyCode, down: bool) -> bool {
+        match code {
+            KeyCode::ShiftLeft => self.shift[0] = down,
+            KeyCode::ShiftRight => self.shift[1] = down,
+            KeyCode::ControlLeft => self.ctrl[0] = down,
+            KeyCode::ControlRight => self.ctrl[1] = down,
+            KeyCode::AltLeft => self.alt[0] = down,
+            KeyCode::AltRight => self.alt[1] = down,
+            KeyCode::CapsLock => return self.caps_lock.key(down),
+            KeyCode::NumpadLock => return self.num_lock.key(down),
+            KeyCode::ScrollLock => return self.scroll_lock.key(down),
+            _ => {}
+        }
+        false
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(decoder: &mut Decoder, bytes: &[u8]) -> Option<KeyEvent> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.add_byte(byte))
+            .last()
+    }
+
+    #[test_case]
+    fn keys_go_down_and_up() {
+        let mut decoder = Decoder::new();
+        let press = feed(&mut decoder, &[0x1e]).unwrap();
+        assert_eq!(press.code, KeyCode::A);
+        assert!(press.is_press());
+        assert_eq!(press.char, Some('a'));
+        let release = feed(&mut decoder, &[0x9e]).unwrap();
+        assert_eq!(release.state, KeyState::Up);
+        assert_eq!(release.char, None);
+    }
+
+    #[test_case]
+    fn modifiers_are_tracked() {
+        let mut decoder = Decoder::new();
+        let shifted = feed(&mut decoder, &[0x2a, 0x1e]).unwrap();
+        assert!(shifted.modifiers.shift);
+        assert_eq!(shifted.char, Some('A'));
+
+        // Shift up, then Ctrl+C and Alt
+        let ctrl_c = feed(&mut decoder, &[0xaa, 0x1d, 0x2e]).unwrap();
+        assert!(!ctrl_c.modifiers.shift);
+        assert!(ctrl_c.is_ctrl('c'));
+        let alt = feed(&mut decoder, &[0x9d, 0x38, 0x2e]).unwrap();
+        assert_eq!(
+            alt.modifiers,
+            Modifiers {
+                alt: true,
+                num_lock: true,
+                ..Modifiers::default()
+            }
+        );
+    }
+
+    #[test_case]
+    fn set_2_is_decoded() {
+        let mut decoder = Decoder::with_scancode_set(ScancodeSet::Set2);
+        assert_eq!(decoder.scancode_set(), ScancodeSet::Set2);
+        let press = feed(&mut decoder, &[0x1c]).unwrap();
+        assert_eq!(press.char, Some('a'));
+        // Releases are the key's code after 0xf0
+        let release = feed(&mut decoder, &[0xf0, 0x1c]).unwrap();
+        assert_eq!(release.code, KeyCode::A);
+        assert_eq!(release.state, KeyState::Up);
+
+        // Num Lock toggles like Caps Lock
+        feed(&mut decoder, &[0x77, 0x77, 0xf0, 0x77]);
+        assert!(!decoder.modifiers().num_lock);
+    }
+
+    #[test_case]
+    fn caps_lock_toggles_once_per_press() {
+        let mut decoder = Decoder::new();
+        // Held long enough for the keyboard to repeat it
+        feed(&mut decoder, &[0x3a, 0x3a, 0x3a, 0xba]);
+        let key = feed(&mut decoder, &[0x1e]).unwrap();
+        assert!(key.modifiers.caps_lock);
+        assert_eq!(key.char, Some('A'));
+        feed(&mut decoder, &[0x3a, 0xba]);
+        assert!(!decoder.modifiers().caps_lock);
+    }
+
+    #[test_case]
+    fn layouts_switch_at_runtime() {
+        let mut decoder = Decoder::new();
+        set_layout(Layout::Dvorak);
+        assert_eq!(feed(&mut decoder, &[0x10]).unwrap().char, Some('\''));
+        set_layout(Layout::Azerty);
+        assert_eq!(feed(&mut decoder, &[0x10]).unwrap().char, Some('a'));
+        set_layout(Layout::De105);
+        assert_eq!(feed(&mut decoder, &[0x15]).unwrap().char, Some('z'));
+        set_layout(Layout::Us104);
+        assert_eq!(feed(&mut decoder, &[0x15]).unwrap().char, Some('y'));
+        // Shift+2
+        set_layout(Layout::Uk105);
+        assert_eq!(feed(&mut decoder, &[0x2a, 0x03]).unwrap().char, Some('"'));
+        set_layout(Layout::Us104);
+        assert_eq!(feed(&mut decoder, &[0x03]).unwrap().char, Some('@'));
+
+        assert_eq!(Layout::from_name("dvorak"), Some(Layout::Dvorak));
+        assert_eq!(Layout::from_name("qwerty"), None);
+    }
+}
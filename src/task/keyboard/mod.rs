@@ -0,0 +1,412 @@
+//! The keyboard service. [`keyboard_scheduler`] decodes the scancodes the interrupt handler
+//! collects, and hands each key event to the subscribers. Events carry both the key that went
+//! down or up and the character it types with the current [`Layout`].
+//!
+//! A subscriber made with [`subscribe`] gets every key, e.g. to switch virtual terminals. One
+//! made with [`subscribe_focused`] only gets keys while it has the focus, so a shell and a game
+//! can share the keyboard. Keys that nobody would get are kept until somebody does.
+
+use crate::println;
+use crate::ps2::{self, keyboard::Leds};
+use crate::sync::SpinLock;
+use alloc::collections::{BTreeMap, VecDeque};
+use conquer_once::spin::OnceCell;
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use futures_util::{stream::Stream, StreamExt};
+use lazy_static::lazy_static;
+
+mod de105;
+mod decoder;
+
+pub use decoder::{layout, set_layout, Decoder, KeyEvent, Layout, Modifiers};
+pub use pc_keyboard::{KeyCode, KeyState};
+
+/// How many keys each subscriber buffers. When it falls further behind, its oldest keys are
+/// dropped.
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// How many keys are kept while nobody would get them.
+const BACKLOG_CAPACITY: usize = 64;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+lazy_static! {
+    static ref SERVICE: SpinLock<Service> = SpinLock::new("keyboard service", Service::new());
+}
+
+/// Identifies a subscriber, e.g. to give it the focus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubscriberId(u64);
+
+struct Service {
+    subscribers: BTreeMap<SubscriberId, Subscriber>,
+    next_id: u64,
+    focus: Option<SubscriberId>,
+    /// Keys that arrived while nobody would get them.
+    backlog: VecDeque<KeyEvent>,
+}
+
+struct Subscriber {
+    /// Whether the subscriber only gets keys while it has the focus.
+    focused_only: bool,
+    keys: VecDeque<KeyEvent>,
+    /// Keys dropped because the subscriber fell behind.
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+impl Subscriber {
+    fn push(&mut self, key: KeyEvent) {
+        if self.keys.len() == SUBSCRIBER_CAPACITY {
+            self.keys.pop_front();
+            self.dropped += 1;
+        }
+        self.keys.push_back(key);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn receives(focus: Option<SubscriberId>, id: SubscriberId, subscriber: &Subscriber) -> bool {
+    !subscriber.focused_only || focus == Some(id)
+}
+
+impl Service {
+    fn new() -> Self {
+        Service {
+            subscribers: BTreeMap::new(),
+            next_id: 0,
+            focus: None,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    fn subscribe(&mut self, focused_only: bool) -> SubscriberId {
+        let id = SubscriberId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.insert(
+            id,
+            Subscriber {
+                focused_only,
+                keys: VecDeque::with_capacity(SUBSCRIBER_CAPACITY),
+                dropped: 0,
+                waker: None,
+            },
+        );
+        self.flush_backlog();
+        id
+    }
+
+    fn unsubscribe(&mut self, id: SubscriberId) {
+        self.subscribers.remove(&id);
+        if self.focus == Some(id) {
+            self.focus = None;
+        }
+    }
+
+    fn set_focus(&mut self, focus: Option<SubscriberId>) {
+        self.focus = focus.filter(|id| self.subscribers.contains_key(id));
+        self.flush_backlog();
+    }
+
+    /// Queues `key` for every subscriber that gets it, or keeps it if there is none. Returns
+    /// how many subscribers got it.
+    fn publish(&mut self, key: KeyEvent) -> usize {
+        let focus = self.focus;
+        let mut delivered = 0;
+        for (&id, subscriber) in self.subscribers.iter_mut() {
+            if receives(focus, id, subscriber) {
+                subscriber.push(key);
+                delivered += 1;
+            }
+        }
+        if delivered == 0 {
+            if self.backlog.len() == BACKLOG_CAPACITY {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(key);
+        }
+        delivered
+    }
+
+    /// Hands the kept keys to the subscribers, once there is one that gets them.
+    fn flush_backlog(&mut self) {
+        let focus = self.focus;
+        let anyone = self
+            .subscribers
+            .iter()
+            .any(|(&id, subscriber)| receives(focus, id, subscriber));
+        if anyone {
+            for key in core::mem::take(&mut self.backlog) {
+                self.publish(key);
+            }
+        }
+    }
+
+    fn poll_recv(&mut self, id: SubscriberId, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        let subscriber = self.subscribers.get_mut(&id).unwrap();
+        match subscriber.keys.pop_front() {
+            Some(key) => Poll::Ready(key),
+            None => {
+                subscriber.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Subscribes to every key, no matter which subscriber has the focus.
+pub fn subscribe() -> KeyStream {
+    KeyStream {
+        id: SERVICE.lock().subscribe(false),
+    }
+}
+
+/// Subscribes to the keys typed while the subscriber has the focus.
+pub fn subscribe_focused() -> KeyStream {
+    KeyStream {
+        id: SERVICE.lock().subscribe(true),
+    }
+}
+
+/// Gives the focus to the subscriber, taking it from the one that had it. Does nothing if there
+/// is no such subscriber.
+pub fn set_focus(id: SubscriberId) {
+    SERVICE.lock().set_focus(Some(id));
+}
+
+pub fn clear_focus() {
+    SERVICE.lock().set_focus(None);
+}
+
+pub fn focus() -> Option<SubscriberId> {
+    SERVICE.lock().focus
+}
+
+/// Delivers `key` to the subscribers as if it was typed, e.g. for input from the serial port.
+/// Returns how many got it.
+pub fn publish(key: KeyEvent) -> usize {
+    SERVICE.lock().publish(key)
+}
+
+/// The keys for one subscriber, also as a [`Stream`] that never ends. Dropping it unsubscribes.
+pub struct KeyStream {
+    id: SubscriberId,
+}
+
+impl KeyStream {
+    pub fn id(&self) -> SubscriberId {
+        self.id
+    }
+
+    /// Takes the focus, see [`set_focus`].
+    pub fn focus(&self) {
+        set_focus(self.id);
+    }
+
+    pub fn has_focus(&self) -> bool {
+        focus() == Some(self.id)
+    }
+
+    /// Waits for the next key.
+    pub async fn recv(&mut self) -> KeyEvent {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<KeyEvent> {
+        let mut service = SERVICE.lock();
+        let subscriber = service.subscribers.get_mut(&self.id).unwrap();
+        subscriber.keys.pop_front()
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        SERVICE.lock().poll_recv(self.id, cx)
+    }
+
+    /// How many keys were dropped because this subscriber fell behind.
+    pub fn dropped(&self) -> u64 {
+        SERVICE.lock().subscribers[&self.id].dropped
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
+
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        SERVICE.lock().unsubscribe(self.id);
+    }
+}
+
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE.init_once(|| ArrayQueue::new(100));
+        ScancodeStream { _private: () }
+    }
+}
+
+/// Decodes the scancodes and hands the key events to the subscribers.
+pub async fn keyboard_scheduler() {
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = Decoder::with_scancode_set(ps2::keyboard::scancode_set());
+    let mut leds = Leds::default();
+
+    while let Some(scancode) = scancodes.next().await {
+        if decoder.scancode_set() != ps2::keyboard::scancode_set() {
+            decoder = Decoder::with_scancode_set(ps2::keyboard::scancode_set());
+        }
+        if let Some(event) = decoder.add_byte(scancode) {
+            let modifiers = event.modifiers;
+            let lit = Leds {
+                scroll_lock: modifiers.scroll_lock,
+                num_lock: modifiers.num_lock,
+                caps_lock: modifiers.caps_lock,
+            };
+            if lit != leds {
+                leds = lit;
+                // Without a PS/2 keyboard there are no lights to set
+                let _ = ps2::keyboard::set_leds(leds);
+            }
+            publish(event);
+        }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("Scancode queue not initialized");
+
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if let Err(_) = queue.push(scancode) {
+            println!("WARNING: scancode queue full; dropping keyboard input");
+        } else {
+            WAKER.wake();
+        }
+    } else {
+        println!("WARNING: scancode queue initialized");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent {
+            code: KeyCode::A,
+            state: KeyState::Down,
+            modifiers: Modifiers::default(),
+            char: Some(c),
+        }
+    }
+
+    fn keys(service: &Service, id: SubscriberId) -> VecDeque<KeyEvent> {
+        service.subscribers[&id].keys.clone()
+    }
+
+    #[test_case]
+    fn keys_reach_every_subscriber() {
+        let mut service = Service::new();
+        let first = service.subscribe(false);
+        let second = service.subscribe(false);
+        assert_eq!(service.publish(key('a')), 2);
+        assert_eq!(keys(&service, first), [key('a')]);
+        assert_eq!(keys(&service, second), [key('a')]);
+
+        service.unsubscribe(first);
+        assert_eq!(service.publish(key('b')), 1);
+    }
+
+    #[test_case]
+    fn focused_subscribers_need_the_focus() {
+        let mut service = Service::new();
+        let switcher = service.subscribe(false);
+        let shell = service.subscribe(true);
+        let game = service.subscribe(true);
+        service.set_focus(Some(game));
+        service.publish(key('a'));
+        service.set_focus(Some(shell));
+        service.publish(key('b'));
+        assert_eq!(keys(&service, switcher), [key('a'), key('b')]);
+        assert_eq!(keys(&service, shell), [key('b')]);
+        assert_eq!(keys(&service, game), [key('a')]);
+
+        // Unsubscribing drops the focus
+        service.unsubscribe(shell);
+        assert_eq!(service.focus, None);
+    }
+
+    #[test_case]
+    fn keys_are_kept_until_somebody_gets_them() {
+        let mut service = Service::new();
+        assert_eq!(service.publish(key('a')), 0);
+        let shell = service.subscribe(true);
+        assert_eq!(service.publish(key('b')), 0);
+        assert!(keys(&service, shell).is_empty());
+
+        service.set_focus(Some(shell));
+        assert_eq!(keys(&service, shell), [key('a'), key('b')]);
+        assert!(service.backlog.is_empty());
+    }
+
+    #[test_case]
+    fn slow_subscribers_drop_the_oldest_keys() {
+        let mut service = Service::new();
+        let id = service.subscribe(false);
+        for i in 0..SUBSCRIBER_CAPACITY + 3 {
+            service.publish(key(char::from_digit(i as u32 % 10, 10).unwrap()));
+        }
+        let subscriber = &service.subscribers[&id];
+        assert_eq!(subscriber.keys.len(), SUBSCRIBER_CAPACITY);
+        assert_eq!(subscriber.dropped, 3);
+        assert_eq!(subscriber.keys[0], key('3'));
+    }
+
+    #[test_case]
+    fn backlog_drops_the_oldest_keys() {
+        let mut service = Service::new();
+        for _ in 0..BACKLOG_CAPACITY {
+            service.publish(key('a'));
+        }
+        service.publish(key('b'));
+        assert_eq!(service.backlog.len(), BACKLOG_CAPACITY);
+        assert_eq!(service.backlog.back(), Some(&key('b')));
+    }
+}
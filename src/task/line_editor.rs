@@ -0,0 +1,458 @@
+//! Reading lines from the keyboard, with editing, history and tab completion, e.g. for a shell.
+//!
+//! The line is drawn on the framebuffer console as it is edited:
+//!
+//! - Backspace and Delete remove the character before and under the cursor
+//! - the arrow keys, Home and End move the cursor, or go through the history
+//! - Ctrl+U removes everything before the cursor, Ctrl+W the word before it
+//! - Tab asks the [`Completer`] what the word before the cursor could be
+
+use crate::task::keyboard::{self, KeyCode, KeyEvent, KeyStream};
+use crate::{println, vga};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// How many lines [`LineEditor`] remembers.
+const HISTORY_CAPACITY: usize = 100;
+
+/// Reads a line without history or completion. See [`LineEditor`].
+pub async fn read_line(prompt: &str) -> String {
+    LineEditor::new().read_line(prompt).await
+}
+
+/// Offers completions when Tab is pressed. Closures taking the line and the word work too.
+pub trait Completer: Send {
+    /// Returns what `word` could be completed to. It is the text from the last space up to the
+    /// cursor, and `line` all text before the cursor.
+    fn complete(&mut self, line: &str, word: &str) -> Vec<String>;
+}
+
+impl<F> Completer for F
+where
+    F: FnMut(&str, &str) -> Vec<String> + Send,
+{
+    fn complete(&mut self, line: &str, word: &str) -> Vec<String> {
+        self(line, word)
+    }
+}
+
+/// A session of reading lines, which share a history.
+///
+/// It only gets keys while it reads a line, as it takes the keyboard focus then.
+pub struct LineEditor {
+    keys: KeyStream,
+    line: Line,
+    history: VecDeque<String>,
+    /// The history entry shown, counted from the newest, or `None` while editing a new line.
+    browsing: Option<usize>,
+    /// The new line, kept while going through the history.
+    draft: Vec<char>,
+    completer: Option<Box<dyn Completer>>,
+}
+
+/// What a key did to the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Edit {
+    Changed,
+    Unchanged,
+    /// Tab found several completions, which should be listed.
+    Candidates(Vec<String>),
+    Done,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            keys: keyboard::subscribe_focused(),
+            line: Line::default(),
+            history: VecDeque::new(),
+            browsing: None,
+            draft: Vec::new(),
+            completer: None,
+        }
+    }
+
+    pub fn set_completer(&mut self, completer: impl Completer + 'static) {
+        self.completer = Some(Box::new(completer));
+    }
+
+    /// Lines read so far, oldest first. Empty lines and repeats are left out.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Shows `prompt`, and returns the line once Enter is pressed.
+    pub async fn read_line(&mut self, prompt: &str) -> String {
+        self.keys.focus();
+        self.line = Line::default();
+        self.browsing = None;
+        self.render(prompt, true);
+        loop {
+            let key = self.keys.recv().await;
+            match self.handle_key(&key) {
+                Edit::Changed => self.render(prompt, true),
+                Edit::Unchanged => {}
+                Edit::Candidates(candidates) => {
+                    self.render(prompt, false);
+                    println!();
+                    println!("{}", candidates.join("  "));
+                    self.render(prompt, true);
+                }
+                Edit::Done => break,
+            }
+        }
+        self.render(prompt, false);
+        println!();
+        if self.keys.has_focus() {
+            keyboard::clear_focus();
+        }
+
+        let line = self.line.text();
+        if !line.is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_CAPACITY {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    fn render(&self, prompt: &str, cursor: bool) {
+        let mut text = String::from(prompt);
+        text.extend(&self.line.chars);
+        let cursor = cursor.then(|| prompt.chars().count() + self.line.cursor);
+        vga::redraw_line(&text, cursor);
+    }
+
+    fn handle_key(&mut self, key: &KeyEvent) -> Edit {
+        if !key.is_press() {
+            return Edit::Unchanged;
+        }
+        let line = &mut self.line;
+        let changed = match key.code {
+            KeyCode::Enter | KeyCode::NumpadEnter => return Edit::Done,
+            KeyCode::Tab => return self.complete(),
+            KeyCode::ArrowUp => self.browse(1),
+            KeyCode::ArrowDown => self.browse(-1),
+            KeyCode::Backspace => line.backspace(),
+            KeyCode::Delete => line.delete(),
+            KeyCode::ArrowLeft => line.move_to(line.cursor.wrapping_sub(1)),
+            KeyCode::ArrowRight => line.move_to(line.cursor + 1),
+            KeyCode::Home => line.move_to(0),
+            KeyCode::End => line.move_to(line.chars.len()),
+            _ if key.is_ctrl('u') => line.remove_before(0),
+            _ if key.is_ctrl('w') => line.remove_before(line.word_start()),
+            _ => match key.char {
+                Some(c) if !c.is_control() && !key.modifiers.ctrl && !key.modifiers.alt => {
+                    line.insert(&[c]);
+                    true
+                }
+                _ => false,
+            },
+        };
+        if changed {
+            Edit::Changed
+        } else {
+            Edit::Unchanged
+        }
+    }
+
+    /// Shows the history entry `steps` older than the one shown, or newer if negative. Going
+    /// past the newest one brings back the new line.
+    fn browse(&mut self, steps: isize) -> bool {
+        let shown = self.browsing.map_or(0, |entry| entry + 1) as isize;
+        let wanted = (shown + steps).clamp(0, self.history.len() as isize) as usize;
+        if wanted == shown as usize {
+            return false;
+        }
+        if self.browsing.is_none() {
+            self.draft = self.line.chars.clone();
+        }
+        let chars = match wanted {
+            0 => core::mem::take(&mut self.draft),
+            _ => self.history[self.history.len() - wanted].chars().collect(),
+        };
+        self.browsing = wanted.checked_sub(1);
+        self.line = Line::new(chars);
+        true
+    }
+
+    fn complete(&mut self) -> Edit {
+        let completer = match &mut self.completer {
+            Some(completer) => completer,
+            None => return Edit::Unchanged,
+        };
+        let line = &mut self.line;
+        let start = line.token_start(line.cursor);
+        let before: String = line.chars[..line.cursor].iter().collect();
+        let word: String = line.chars[start..line.cursor].iter().collect();
+        let candidates = completer.complete(&before, &word);
+
+        let prefix = match common_prefix(&candidates) {
+            Some(prefix) => prefix,
+            None => return Edit::Unchanged,
+        };
+        let mut completed: Vec<char> = prefix.chars().collect();
+        if candidates.len() == 1 {
+            completed.push(' ');
+        }
+        if completed.len() > line.cursor - start {
+            line.remove_before(start);
+            line.insert(&completed);
+            Edit::Changed
+        } else if candidates.len() > 1 {
+            // Nothing more in common, so show what there is to choose from
+            Edit::Candidates(candidates)
+        } else {
+            Edit::Unchanged
+        }
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The longest start all of `candidates` share, or `None` if there are none.
+fn common_prefix(candidates: &[String]) -> Option<&str> {
+    let (first, rest) = candidates.split_first()?;
+    let len = rest.iter().fold(first.len(), |len, candidate| {
+        first[..len]
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.len()), |((i, _), _)| i)
+    });
+    Some(&first[..len])
+}
+
+/// The text being edited and the cursor in it, counted in characters.
+#[derive(Debug, Default)]
+struct Line {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl Line {
+    /// A line with the cursor at its end.
+    fn new(chars: Vec<char>) -> Self {
+        let cursor = chars.len();
+        Line { chars, cursor }
+    }
+
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn insert(&mut self, chars: &[char]) {
+        self.chars
+            .splice(self.cursor..self.cursor, chars.iter().copied());
+        self.cursor += chars.len();
+    }
+
+    /// Moves the cursor, unless that would leave the line.
+    fn move_to(&mut self, cursor: usize) -> bool {
+        if cursor > self.chars.len() || cursor == self.cursor {
+            return false;
+        }
+        self.cursor = cursor;
+        true
+    }
+
+    fn backspace(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.remove_before(self.cursor - 1)
+    }
+
+    fn delete(&mut self) -> bool {
+        if self.cursor == self.chars.len() {
+            return false;
+        }
+        self.chars.remove(self.cursor);
+        true
+    }
+
+    /// Removes the text from `start` up to the cursor.
+    fn remove_before(&mut self, start: usize) -> bool {
+        if start == self.cursor {
+            return false;
+        }
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+        true
+    }
+
+    /// Where the text since the last space before `end` starts.
+    fn token_start(&self, end: usize) -> usize {
+        self.chars[..end]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |space| space + 1)
+    }
+
+    /// Where the word before the cursor starts, skipping spaces right before the cursor.
+    fn word_start(&self) -> usize {
+        let end = self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| !c.is_whitespace())
+            .map_or(0, |last| last + 1);
+        self.token_start(end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::keyboard::{KeyState, Modifiers};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn press(code: KeyCode) -> KeyEvent {
+        KeyEvent {
+            code,
+            state: KeyState::Down,
+            modifiers: Modifiers::default(),
+            char: None,
+        }
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent {
+            modifiers: Modifiers {
+                ctrl: true,
+                ..Modifiers::default()
+            },
+            char: Some(c),
+            ..press(KeyCode::A)
+        }
+    }
+
+    fn type_text(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            let key = KeyEvent {
+                char: Some(c),
+                ..press(KeyCode::A)
+            };
+            editor.handle_key(&key);
+        }
+    }
+
+    fn keys(editor: &mut LineEditor, codes: &[KeyCode]) {
+        for &code in codes {
+            editor.handle_key(&press(code));
+        }
+    }
+
+    #[test_case]
+    fn keys_edit_the_line() {
+        let mut editor = LineEditor::new();
+        type_text(&mut editor, "helo");
+        keys(&mut editor, &[KeyCode::ArrowLeft]);
+        type_text(&mut editor, "l");
+        assert_eq!(editor.line.text(), "hello");
+
+        keys(&mut editor, &[KeyCode::Home, KeyCode::Delete]);
+        type_text(&mut editor, "j");
+        keys(&mut editor, &[KeyCode::End, KeyCode::Backspace]);
+        assert_eq!(editor.line.text(), "jell");
+        // The cursor stays on the line
+        keys(&mut editor, &[KeyCode::ArrowRight, KeyCode::Delete]);
+        assert_eq!(editor.line.cursor, 4);
+
+        // Control characters and releases are not typed
+        type_text(&mut editor, "\u{1b}");
+        editor.handle_key(&KeyEvent {
+            state: KeyState::Up,
+            char: Some('x'),
+            ..press(KeyCode::X)
+        });
+        assert_eq!(editor.line.text(), "jell");
+        assert_eq!(editor.handle_key(&press(KeyCode::Enter)), Edit::Done);
+    }
+
+    #[test_case]
+    fn ctrl_removes_words_and_lines() {
+        let mut editor = LineEditor::new();
+        type_text(&mut editor, "echo some  words  ");
+        editor.handle_key(&ctrl('w'));
+        assert_eq!(editor.line.text(), "echo some  ");
+        editor.handle_key(&ctrl('w'));
+        assert_eq!(editor.line.text(), "echo ");
+        type_text(&mut editor, "more");
+        keys(&mut editor, &[KeyCode::ArrowLeft, KeyCode::ArrowLeft]);
+        editor.handle_key(&ctrl('u'));
+        assert_eq!(editor.line.text(), "re");
+        assert_eq!(editor.line.cursor, 0);
+    }
+
+    #[test_case]
+    fn history_is_browsed() {
+        let mut editor = LineEditor::new();
+        editor
+            .history
+            .extend(["first".to_string(), "second".to_string()]);
+        type_text(&mut editor, "draft");
+        keys(&mut editor, &[KeyCode::ArrowUp]);
+        assert_eq!(editor.line.text(), "second");
+        keys(&mut editor, &[KeyCode::ArrowUp, KeyCode::ArrowUp]);
+        assert_eq!(editor.line.text(), "first");
+        assert_eq!(editor.line.cursor, 5);
+        keys(&mut editor, &[KeyCode::ArrowDown, KeyCode::ArrowDown]);
+        assert_eq!(editor.line.text(), "draft");
+        assert_eq!(
+            editor.handle_key(&press(KeyCode::ArrowDown)),
+            Edit::Unchanged
+        );
+    }
+
+    #[test_case]
+    fn tab_completes_the_word() {
+        let mut editor = LineEditor::new();
+        editor.set_completer(|_: &str, word: &str| {
+            ["help", "hello", "layout"]
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| command.to_string())
+                .collect()
+        });
+        type_text(&mut editor, "la");
+        keys(&mut editor, &[KeyCode::Tab]);
+        assert_eq!(editor.line.text(), "layout ");
+        // The next word is empty, which all commands start with
+        assert!(matches!(
+            editor.handle_key(&press(KeyCode::Tab)),
+            Edit::Candidates(candidates) if candidates.len() == 3
+        ));
+
+        editor.line = Line::default();
+        type_text(&mut editor, "h");
+        keys(&mut editor, &[KeyCode::Tab]);
+        assert_eq!(editor.line.text(), "hel");
+        assert_eq!(
+            editor.handle_key(&press(KeyCode::Tab)),
+            Edit::Candidates(vec!["help".to_string(), "hello".to_string()])
+        );
+        type_text(&mut editor, "x");
+        assert_eq!(editor.handle_key(&press(KeyCode::Tab)), Edit::Unchanged);
+    }
+
+    #[test_case]
+    fn common_prefixes_are_found() {
+        let candidates =
+            |words: &[&str]| -> Vec<String> { words.iter().map(|word| word.to_string()).collect() };
+        assert_eq!(common_prefix(&[]), None);
+        assert_eq!(common_prefix(&candidates(&["abc"])), Some("abc"));
+        assert_eq!(
+            common_prefix(&candidates(&["abcd", "abxy", "ab"])),
+            Some("ab")
+        );
+        assert_eq!(common_prefix(&candidates(&["äb", "äc"])), Some("ä"));
+        assert_eq!(common_prefix(&candidates(&["a", "b"])), Some(""));
+    }
+}
@@ -1,8 +1,16 @@
+pub mod blocking;
+pub mod context;
 pub mod executor;
 pub mod keyboard;
+pub mod line_editor;
+pub mod mouse;
+pub mod policy;
 pub mod scheduler;
 pub mod simple_executor;
+pub mod sync;
 pub mod thread;
+pub mod timer;
+pub mod wait_queue;
 
 use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -14,22 +22,58 @@ use core::{
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: TaskPriority,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Self::with_priority(future, TaskPriority::default())
+    }
+
+    pub fn with_priority(
+        future: impl Future<Output = ()> + Send + 'static,
+        priority: TaskPriority,
+    ) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
             future: Box::pin(future),
         }
     }
 
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// How urgently a task is polled. The executor polls ready tasks of a higher priority first, but
+/// still gets to lower ones now and then, so they cannot starve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TaskPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl TaskPriority {
+    const LEVELS: usize = 3;
+
+    fn level(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for TaskPriority {
+    fn default() -> Self {
+        TaskPriority::Normal
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
@@ -0,0 +1,371 @@
+//! Mouse input. The interrupt handler collects the bytes the PS/2 mouse sends, and
+//! [`MouseStream`] decodes their packets into movement, button and scroll events.
+
+use crate::ps2::{self, DeviceType};
+use alloc::collections::VecDeque;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Whether a [`MouseStream`] exists.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Set in the first byte of every packet, which is how a stream that starts halfway through a
+/// packet finds the next one.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    /// The fourth button of a five button mouse, usually on its side.
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    pub const ALL: [MouseButton; 5] = [
+        MouseButton::Left,
+        MouseButton::Right,
+        MouseButton::Middle,
+        MouseButton::Back,
+        MouseButton::Forward,
+    ];
+
+    fn bit(self) -> u8 {
+        match self {
+            MouseButton::Left => LEFT,
+            MouseButton::Right => RIGHT,
+            MouseButton::Middle => MIDDLE,
+            MouseButton::Back => 1 << 3,
+            MouseButton::Forward => 1 << 4,
+        }
+    }
+}
+
+/// Which buttons are held.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub fn is_pressed(self, button: MouseButton) -> bool {
+        self.0 & button.bit() != 0
+    }
+}
+
+/// Everything the mouse reports at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// Movement to the right.
+    pub dx: i16,
+    /// Movement downwards, like screen coordinates.
+    pub dy: i16,
+    /// How far the wheel turned. Positive scrolls down, towards the user.
+    pub scroll: i8,
+    pub buttons: Buttons,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Movement since the last event, with `dy` growing downwards.
+    Move {
+        dx: i16,
+        dy: i16,
+    },
+    Button {
+        button: MouseButton,
+        pressed: bool,
+    },
+    /// Positive scrolls down.
+    Scroll(i8),
+}
+
+/// Puts the bytes the mouse sends together into packets. Wheel mice send four bytes per
+/// packet instead of three.
+pub struct PacketDecoder {
+    device: DeviceType,
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub fn new(device: DeviceType) -> Self {
+        PacketDecoder {
+            device,
+            bytes: [0; 4],
+            len: 0,
+        }
+    }
+
+    fn packet_size(&self) -> usize {
+        match self.device {
+            DeviceType::WheelMouse | DeviceType::FiveButtonMouse => 4,
+            _ => 3,
+        }
+    }
+
+    /// Feeds the next byte from the mouse. Returns the packet once it is complete.
+    pub fn add_byte(&mut self, byte: u8) -> Option<Packet> {
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            // Not the start of a packet
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size() {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> Packet {
+        let [flags, x, y, extra] = self.bytes;
+        // The mouse gives up on reporting movement that was too fast
+        let (dx, dy) = if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            (0, 0)
+        } else {
+            (
+                movement(x, flags & X_SIGN != 0),
+                -movement(y, flags & Y_SIGN != 0),
+            )
+        };
+        let mut buttons = flags & (LEFT | RIGHT | MIDDLE);
+        let scroll = match self.device {
+            DeviceType::WheelMouse => extra as i8,
+            DeviceType::FiveButtonMouse => {
+                buttons |= (extra >> 1) & (MouseButton::Back.bit() | MouseButton::Forward.bit());
+                // Only the low four bits are the wheel
+                ((extra << 4) as i8) >> 4
+            }
+            _ => 0,
+        };
+        Packet {
+            dx,
+            dy,
+            scroll,
+            buttons: Buttons(buttons),
+        }
+    }
+}
+
+/// Movement is nine bits, with the sign in the first byte of the packet.
+fn movement(value: u8, negative: bool) -> i16 {
+    if negative {
+        value as i16 - 256
+    } else {
+        value as i16
+    }
+}
+
+/// The mouse's events. Never ends.
+///
+/// There can only be one, as packets split between several streams could not be decoded.
+pub struct MouseStream {
+    decoder: PacketDecoder,
+    buttons: Buttons,
+    events: VecDeque<MouseEvent>,
+}
+
+impl MouseStream {
+    /// Returns `None` while another stream exists.
+    pub fn new() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let queue = BYTE_QUEUE.get_or_init(|| ArrayQueue::new(100));
+        // Left over from an earlier stream
+        while queue.pop().is_some() {}
+        let device = ps2::mouse::device().unwrap_or(DeviceType::Mouse);
+        Some(MouseStream {
+            decoder: PacketDecoder::new(device),
+            buttons: Buttons::default(),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// The buttons held as of the last event.
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    /// Queues an event for each thing that changed with the packet.
+    fn push_packet(&mut self, packet: Packet) {
+        if packet.dx != 0 || packet.dy != 0 {
+            self.events.push_back(MouseEvent::Move {
+                dx: packet.dx,
+                dy: packet.dy,
+            });
+        }
+        for button in MouseButton::ALL {
+            let pressed = packet.buttons.is_pressed(button);
+            if pressed != self.buttons.is_pressed(button) {
+                self.events
+                    .push_back(MouseEvent::Button { button, pressed });
+            }
+        }
+        if packet.scroll != 0 {
+            self.events.push_back(MouseEvent::Scroll(packet.scroll));
+        }
+        self.buttons = packet.buttons;
+    }
+}
+
+impl Drop for MouseStream {
+    fn drop(&mut self) {
+        TAKEN.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = self.get_mut();
+        let queue = BYTE_QUEUE.try_get().expect("Mouse queue not initialized");
+
+        loop {
+            if let Some(event) = stream.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            let byte = match queue.pop() {
+                Some(byte) => byte,
+                None => {
+                    WAKER.register(cx.waker());
+                    match queue.pop() {
+                        Some(byte) => {
+                            WAKER.take();
+                            byte
+                        }
+                        None => return Poll::Pending,
+                    }
+                }
+            };
+            if let Some(packet) = stream.decoder.add_byte(byte) {
+                stream.push_packet(packet);
+            }
+        }
+    }
+}
+
+pub(crate) fn add_byte(byte: u8) {
+    // Nobody listens to the mouse before the stream is made. Bytes that do not fit are
+    // dropped, the stream finds the start of the next packet again.
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(device: DeviceType, bytes: &[u8]) -> Option<Packet> {
+        let mut decoder = PacketDecoder::new(device);
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.add_byte(byte))
+            .last()
+    }
+
+    #[test_case]
+    fn packets_are_decoded() {
+        let packet = decode(DeviceType::Mouse, &[0x09, 5, 3]).unwrap();
+        assert_eq!((packet.dx, packet.dy), (5, -3));
+        assert!(packet.buttons.is_pressed(MouseButton::Left));
+        assert!(!packet.buttons.is_pressed(MouseButton::Right));
+
+        // Left and down
+        let packet = decode(DeviceType::Mouse, &[0x38, 0xfe, 0xfd]).unwrap();
+        assert_eq!((packet.dx, packet.dy), (-2, 3));
+
+        let overflow = decode(DeviceType::Mouse, &[0x48, 0xff, 0x00]).unwrap();
+        assert_eq!((overflow.dx, overflow.dy), (0, 0));
+    }
+
+    #[test_case]
+    fn decoder_finds_the_start_of_a_packet() {
+        // The first two bytes are the end of a packet that was missed
+        let packet = decode(DeviceType::Mouse, &[0x05, 0x00, 0x0a, 1, 1]).unwrap();
+        assert!(packet.buttons.is_pressed(MouseButton::Right));
+        assert_eq!((packet.dx, packet.dy), (1, -1));
+    }
+
+    #[test_case]
+    fn wheels_are_decoded() {
+        assert_eq!(
+            decode(DeviceType::Mouse, &[0x08, 0, 0, 0xff])
+                .unwrap()
+                .scroll,
+            0
+        );
+        assert_eq!(
+            decode(DeviceType::WheelMouse, &[0x08, 0, 0, 0xff])
+                .unwrap()
+                .scroll,
+            -1
+        );
+
+        let packet = decode(DeviceType::FiveButtonMouse, &[0x08, 0, 0, 0x21]).unwrap();
+        assert_eq!(packet.scroll, 1);
+        assert!(packet.buttons.is_pressed(MouseButton::Forward));
+        assert!(!packet.buttons.is_pressed(MouseButton::Back));
+        let packet = decode(DeviceType::FiveButtonMouse, &[0x08, 0, 0, 0x1e]).unwrap();
+        assert_eq!(packet.scroll, -2);
+        assert!(packet.buttons.is_pressed(MouseButton::Back));
+    }
+
+    #[test_case]
+    fn packets_turn_into_events() {
+        let mut stream = MouseStream::new().unwrap();
+        stream.decoder = PacketDecoder::new(DeviceType::WheelMouse);
+        for &byte in &[0x09, 2, 0, 0, 0x08, 0, 0, 1] {
+            if let Some(packet) = stream.decoder.add_byte(byte) {
+                stream.push_packet(packet);
+            }
+        }
+        let events: alloc::vec::Vec<_> = stream.events.drain(..).collect();
+        assert_eq!(
+            events,
+            [
+                MouseEvent::Move { dx: 2, dy: 0 },
+                MouseEvent::Button {
+                    button: MouseButton::Left,
+                    pressed: true
+                },
+                MouseEvent::Button {
+                    button: MouseButton::Left,
+                    pressed: false
+                },
+                MouseEvent::Scroll(1),
+            ]
+        );
+        assert_eq!(stream.buttons(), Buttons::default());
+    }
+
+    #[test_case]
+    fn only_one_stream_exists_at_a_time() {
+        let stream = MouseStream::new().unwrap();
+        assert!(MouseStream::new().is_none());
+        drop(stream);
+        assert!(MouseStream::new().is_some());
+    }
+}
@@ -0,0 +1,358 @@
+//! Scheduling policies decide which runnable thread runs next and when the running thread has
+//! to make way for another one.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::thread::ThreadId;
+use crate::time;
+
+/// How important a thread is. Higher runs first under [`FixedPriority`], and gets a larger share
+/// of the CPU under [`FairShare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    pub const LOW: Priority = Priority(5);
+    pub const NORMAL: Priority = Priority(10);
+    pub const HIGH: Priority = Priority(20);
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::NORMAL
+    }
+}
+
+/// The scheduler calls these with interrupts disabled, some of them from interrupt handlers, so
+/// only [`add_thread`](SchedulingPolicy::add_thread) may allocate.
+pub trait SchedulingPolicy: Send {
+    fn name(&self) -> &'static str;
+
+    /// A thread was created. Called before it is first enqueued.
+    fn add_thread(&mut self, tid: ThreadId, priority: Priority);
+
+    /// The thread has exited and is never enqueued again.
+    fn remove_thread(&mut self, tid: ThreadId);
+
+    /// The thread is runnable and waits for its turn.
+    fn enqueue(&mut self, tid: ThreadId);
+
+    /// Takes the thread that runs next off the queue.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// The running thread has used up another timer tick. Returns whether it should make way
+    /// for a queued thread.
+    fn tick(&mut self, tid: ThreadId) -> bool;
+
+    fn set_priority(&mut self, tid: ThreadId, priority: Priority);
+}
+
+/// The scheduling policies to choose from at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    FixedPriority,
+    FairShare,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::RoundRobin
+    }
+}
+
+impl Policy {
+    pub fn create(self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            Policy::RoundRobin => Box::new(RoundRobin::new()),
+            Policy::FixedPriority => Box::new(FixedPriority::new()),
+            Policy::FairShare => Box::new(FairShare::new()),
+        }
+    }
+}
+
+/// Runs the threads in turn for a tick each, ignoring their priority.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    threads: usize,
+    queue: VecDeque<ThreadId>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchedulingPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+
+    fn add_thread(&mut self, _tid: ThreadId, _priority: Priority) {
+        self.threads += 1;
+        self.queue
+            .reserve(self.threads.saturating_sub(self.queue.len()));
+    }
+
+    fn remove_thread(&mut self, tid: ThreadId) {
+        self.threads -= 1;
+        self.queue.retain(|&queued| queued != tid);
+    }
+
+    fn enqueue(&mut self, tid: ThreadId) {
+        self.queue.push_back(tid);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.queue.pop_front()
+    }
+
+    fn tick(&mut self, _tid: ThreadId) -> bool {
+        !self.queue.is_empty()
+    }
+
+    fn set_priority(&mut self, _tid: ThreadId, _priority: Priority) {}
+}
+
+/// Always runs the queued thread with the highest priority, taking turns between threads of the
+/// same priority. Lower priority threads only run when no higher one is runnable.
+#[derive(Debug, Default)]
+pub struct FixedPriority {
+    priorities: BTreeMap<ThreadId, Priority>,
+    /// In the order the threads were queued.
+    queue: Vec<ThreadId>,
+}
+
+impl FixedPriority {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn highest_queued(&self) -> Option<(usize, Priority)> {
+        let mut highest: Option<(usize, Priority)> = None;
+        for (index, tid) in self.queue.iter().enumerate() {
+            let priority = self.priorities[tid];
+            // The earliest queued wins among equal priorities
+            if highest.map_or(true, |(_, highest)| priority > highest) {
+                highest = Some((index, priority));
+            }
+        }
+        highest
+    }
+}
+
+impl SchedulingPolicy for FixedPriority {
+    fn name(&self) -> &'static str {
+        "fixed priority"
+    }
+
+    fn add_thread(&mut self, tid: ThreadId, priority: Priority) {
+        self.priorities.insert(tid, priority);
+        self.queue
+            .reserve(self.priorities.len().saturating_sub(self.queue.len()));
+    }
+
+    fn remove_thread(&mut self, tid: ThreadId) {
+        self.priorities.remove(&tid);
+        self.queue.retain(|&queued| queued != tid);
+    }
+
+    fn enqueue(&mut self, tid: ThreadId) {
+        self.queue.push(tid);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (index, _) = self.highest_queued()?;
+        Some(self.queue.remove(index))
+    }
+
+    fn tick(&mut self, tid: ThreadId) -> bool {
+        let current = self.priorities[&tid];
+        self.highest_queued()
+            .map_or(false, |(_, highest)| highest >= current)
+    }
+
+    fn set_priority(&mut self, tid: ThreadId, priority: Priority) {
+        if let Some(entry) = self.priorities.get_mut(&tid) {
+            *entry = priority;
+        }
+    }
+}
+
+/// Shares the CPU between the threads in proportion to their priority.
+///
+/// Every thread has a virtual runtime that grows while it runs, the slower the higher its
+/// priority is, and the queued thread with the lowest one runs next. A thread that has been
+/// blocked for a while therefore runs as soon as it wakes up, which keeps interactive threads
+/// responsive next to busy ones.
+#[derive(Debug, Default)]
+pub struct FairShare {
+    threads: BTreeMap<ThreadId, FairShareEntry>,
+    queue: Vec<ThreadId>,
+    /// The lowest virtual runtime of any runnable thread, never decreasing.
+    min_vruntime: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FairShareEntry {
+    priority: Priority,
+    vruntime: u64,
+}
+
+impl FairShareEntry {
+    /// How much the virtual runtime grows per tick: a tick for a normal priority thread.
+    fn vruntime_per_tick(&self) -> u64 {
+        let weight = self.priority.0 as u64 + 1;
+        time::nanos_per_tick() * (Priority::NORMAL.0 as u64 + 1) / weight
+    }
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lowest_queued(&self) -> Option<(usize, u64)> {
+        let mut lowest: Option<(usize, u64)> = None;
+        for (index, tid) in self.queue.iter().enumerate() {
+            let vruntime = self.threads[tid].vruntime;
+            if lowest.map_or(true, |(_, lowest)| vruntime < lowest) {
+                lowest = Some((index, vruntime));
+            }
+        }
+        lowest
+    }
+}
+
+impl SchedulingPolicy for FairShare {
+    fn name(&self) -> &'static str {
+        "fair share"
+    }
+
+    fn add_thread(&mut self, tid: ThreadId, priority: Priority) {
+        let entry = FairShareEntry {
+            priority,
+            vruntime: self.min_vruntime,
+        };
+        self.threads.insert(tid, entry);
+        self.queue
+            .reserve(self.threads.len().saturating_sub(self.queue.len()));
+    }
+
+    fn remove_thread(&mut self, tid: ThreadId) {
+        self.threads.remove(&tid);
+        self.queue.retain(|&queued| queued != tid);
+    }
+
+    fn enqueue(&mut self, tid: ThreadId) {
+        let entry = self.threads.get_mut(&tid).unwrap();
+        // A thread that slept does not get to make up for all the time it missed
+        entry.vruntime = entry.vruntime.max(self.min_vruntime);
+        self.queue.push(tid);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (index, vruntime) = self.lowest_queued()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(self.queue.remove(index))
+    }
+
+    fn tick(&mut self, tid: ThreadId) -> bool {
+        let entry = self.threads.get_mut(&tid).unwrap();
+        entry.vruntime += entry.vruntime_per_tick();
+        let vruntime = entry.vruntime;
+        let lowest = self.lowest_queued().map(|(_, lowest)| lowest);
+        self.min_vruntime = self
+            .min_vruntime
+            .max(lowest.map_or(vruntime, |lowest| lowest.min(vruntime)));
+        lowest.map_or(false, |lowest| lowest < vruntime)
+    }
+
+    fn set_priority(&mut self, tid: ThreadId, priority: Priority) {
+        if let Some(entry) = self.threads.get_mut(&tid) {
+            entry.priority = priority;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(id: u64) -> ThreadId {
+        unsafe { ThreadId::from_u64(id) }
+    }
+
+    fn add_queued(policy: &mut dyn SchedulingPolicy, threads: &[(u64, Priority)]) {
+        for &(id, priority) in threads {
+            policy.add_thread(tid(id), priority);
+            policy.enqueue(tid(id));
+        }
+    }
+
+    #[test_case]
+    fn round_robin_takes_turns() {
+        let mut policy = RoundRobin::new();
+        add_queued(&mut policy, &[(1, Priority::LOW), (2, Priority::HIGH)]);
+        assert_eq!(policy.pick_next(), Some(tid(1)));
+        assert!(policy.tick(tid(1)));
+        policy.enqueue(tid(1));
+        assert_eq!(policy.pick_next(), Some(tid(2)));
+        assert_eq!(policy.pick_next(), Some(tid(1)));
+        assert!(!policy.tick(tid(1)));
+    }
+
+    #[test_case]
+    fn fixed_priority_runs_highest_first() {
+        let mut policy = FixedPriority::new();
+        add_queued(
+            &mut policy,
+            &[(1, Priority::LOW), (2, Priority::HIGH), (3, Priority::HIGH)],
+        );
+        assert_eq!(policy.pick_next(), Some(tid(2)));
+        // Takes turns with the other high priority thread, but not with the low one
+        assert!(policy.tick(tid(2)));
+        assert_eq!(policy.pick_next(), Some(tid(3)));
+        assert!(!policy.tick(tid(3)));
+        assert_eq!(policy.pick_next(), Some(tid(1)));
+        assert_eq!(policy.pick_next(), None);
+    }
+
+    #[test_case]
+    fn fair_share_favors_higher_priority() {
+        let mut policy = FairShare::new();
+        add_queued(&mut policy, &[(1, Priority::NORMAL), (2, Priority(21))]);
+
+        let mut ran = [0; 2];
+        let mut current = policy.pick_next().unwrap();
+        for _ in 0..300 {
+            ran[current.as_u64() as usize - 1] += 1;
+            if policy.tick(current) {
+                policy.enqueue(current);
+                current = policy.pick_next().unwrap();
+            }
+        }
+        // Twice the weight gets twice the ticks
+        assert!((199..=201).contains(&ran[1]), "{ran:?}");
+    }
+
+    #[test_case]
+    fn fair_share_runs_woken_thread_first() {
+        let mut policy = FairShare::new();
+        add_queued(&mut policy, &[(1, Priority::NORMAL)]);
+        policy.add_thread(tid(2), Priority::NORMAL);
+
+        // Thread 2 is blocked while thread 1 runs for a while
+        assert_eq!(policy.pick_next(), Some(tid(1)));
+        for _ in 0..10 {
+            assert!(!policy.tick(tid(1)));
+        }
+        policy.enqueue(tid(2));
+        assert!(policy.tick(tid(1)));
+        policy.enqueue(tid(1));
+        assert_eq!(policy.pick_next(), Some(tid(2)));
+    }
+}
@@ -1,89 +1,652 @@
-use super::thread::{Registers, Thread, ThreadId};
+use super::context::switch_context;
+use super::policy::{Policy, Priority, SchedulingPolicy};
+use super::thread::{Registers, Thread, ThreadId, ThreadState};
+use super::timer;
+use super::wait_queue::WaitQueue;
+use crate::debug::lockdep::{self, HeldLocks};
 use crate::memory::{lock_frame_allocator, lock_memory_mapper};
-use alloc::collections::{BTreeMap, VecDeque};
-use core::sync::atomic::{AtomicU64, Ordering};
-use spin::{Mutex, Once};
+use crate::sync::SpinLock;
+use crate::{fpu, gdt, time};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::instructions::interrupts::{self, without_interrupts};
 use x86_64::structures::idt::InterruptStackFrameValue;
 
-static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
+static SCHEDULER: Once<SpinLock<Scheduler>> = Once::new();
 static CURRENT_THREAD: AtomicU64 = AtomicU64::new(0);
+/// Threads only run on the bootstrap processor so far, this is its local APIC id.
+static CPU_ID: AtomicU32 = AtomicU32::new(0);
+/// Woken whenever a thread exits, for [`JoinHandle::join`].
+static EXITED: WaitQueue = WaitQueue::new();
 
-pub fn init_scheduler() {
-    SCHEDULER.call_once(|| Mutex::new(Scheduler::new()));
+pub fn init_scheduler(policy: Policy) {
+    let apic_id = unsafe { __cpuid(1) }.ebx >> 24;
+    CPU_ID.store(apic_id, Ordering::Relaxed);
+    SCHEDULER.call_once(|| SpinLock::new("scheduler", Scheduler::new(policy.create())));
 }
 
 pub struct Scheduler {
     threads: BTreeMap<ThreadId, Thread>,
-    queue: VecDeque<ThreadId>,
+    /// Decides between the runnable threads other than the current one.
+    policy: Box<dyn SchedulingPolicy>,
+    /// Sleeping threads and the tick they wake up at.
+    sleepers: Vec<(u64, ThreadId)>,
+    /// Runs when no other thread can. It is not known to the policy and is never queued.
+    idle_thread: ThreadId,
+    /// The tick up to which CPU time has been charged to the threads.
+    accounted_until: u64,
 }
 
 impl Scheduler {
-    fn new() -> Self {
-        let root_thread = Thread::create_root_thread();
+    fn new(mut policy: Box<dyn SchedulingPolicy>) -> Self {
+        let mut root_thread = Thread::create_root_thread();
+        root_thread.last_cpu = Some(current_cpu());
         let root_id = root_thread.tid;
-        let threads = BTreeMap::from([(root_id, root_thread)]);
+        policy.add_thread(root_id, root_thread.priority);
 
+        let mut idle_thread = {
+            let mut mapper = lock_memory_mapper();
+            let mut frame_allocator = lock_frame_allocator();
+            Thread::create_closure(&mut *mapper, &mut *frame_allocator, idle_loop)
+        };
+        idle_thread.name = String::from("idle");
+        idle_thread.priority = Priority(0);
+        let idle_id = idle_thread.tid;
+
+        let threads = BTreeMap::from([(root_id, root_thread), (idle_id, idle_thread)]);
         Scheduler {
             threads,
-            queue: VecDeque::default(),
+            policy,
+            sleepers: Vec::new(),
+            idle_thread: idle_id,
+            accounted_until: time::ticks(),
         }
     }
 
-    fn schedule(&mut self) -> Option<Thread> {
-        self.queue
-            .pop_front()
-            .and_then(|tid| self.threads.get(&tid).copied())
+    /// Charges the ticks since the last call to the current thread.
+    fn account(&mut self) {
+        let now = time::ticks();
+        let elapsed = now - self.accounted_until;
+        self.accounted_until = now;
+        self.threads.get_mut(&current_thread()).unwrap().ticks_run += elapsed;
     }
 
     fn register_thread(&mut self, thread: Thread) {
-        let prev = self.threads.insert(thread.tid, thread);
+        let tid = thread.tid;
+        let prev = self.threads.insert(tid, thread);
         if prev.is_some() {
-            panic!("Thread with id {} already exists", thread.tid.as_u64());
+            panic!("Thread with id {} already exists", tid.as_u64());
+        }
+        // Interrupt handlers wake threads, and must not allocate while doing so
+        let missing = self.threads.len().saturating_sub(self.sleepers.len());
+        self.sleepers.reserve(missing);
+        self.policy.add_thread(tid, self.threads[&tid].priority);
+        self.policy.enqueue(tid);
+    }
+
+    fn state(&self, tid: ThreadId) -> ThreadState {
+        self.threads[&tid].state
+    }
+
+    fn set_state(&mut self, tid: ThreadId, state: ThreadState) {
+        self.threads.get_mut(&tid).unwrap().state = state;
+    }
+
+    fn make_runnable(&mut self, tid: ThreadId) {
+        self.set_state(tid, ThreadState::Runnable);
+        // The current thread is queued again when it is switched away from
+        if tid != current_thread() {
+            self.policy.enqueue(tid);
+        }
+    }
+
+    fn wake(&mut self, tid: ThreadId) -> bool {
+        match self.threads.get(&tid).map(|thread| thread.state) {
+            Some(ThreadState::Blocked) => {
+                self.make_runnable(tid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.sleepers.len() {
+            let (until, tid) = self.sleepers[i];
+            if until <= now {
+                self.sleepers.swap_remove(i);
+                self.make_runnable(tid);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Takes an exited thread out of the scheduler, returning its exit code and the thread so
+    /// its memory can be released.
+    fn remove_exited(&mut self, tid: ThreadId) -> Option<(i32, Thread)> {
+        match self.threads.get(&tid)?.state {
+            ThreadState::Exited(exit_code) => Some((exit_code, self.threads.remove(&tid)?)),
+            _ => None,
         }
-        self.queue.push_back(thread.tid);
     }
 }
 
-pub fn spawn_user(entrypoint: fn() -> !) {
-    let mut mapper = lock_memory_mapper();
-    let mut frame_allocator = lock_frame_allocator();
+/// Locks the scheduler with interrupts disabled. The timer and syscall handlers lock it too, so
+/// it must never be held by a thread that can be preempted.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    without_interrupts(|| f(&mut SCHEDULER.get().unwrap().lock()))
+}
+
+/// Allows waiting for a thread to end. Dropping the handle detaches the thread, whose exit code
+/// is then discarded.
+#[derive(Debug)]
+pub struct JoinHandle {
+    tid: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn thread_id(&self) -> ThreadId {
+        self.tid
+    }
+
+    pub fn is_finished(&self) -> bool {
+        with_scheduler(|scheduler| matches!(scheduler.state(self.tid), ThreadState::Exited(_)))
+    }
+
+    /// Blocks until the thread has ended and returns its exit code.
+    pub fn join(self) -> i32 {
+        let tid = self.tid;
+        core::mem::forget(self);
+        EXITED.wait_while(|| {
+            with_scheduler(|scheduler| !matches!(scheduler.state(tid), ThreadState::Exited(_)))
+        });
+        let (exit_code, thread) = with_scheduler(|scheduler| scheduler.remove_exited(tid)).unwrap();
+        release_thread(thread);
+        exit_code
+    }
+}
 
-    let thread =
-        Thread::create_userspace_entrypoint(&mut *mapper, &mut *frame_allocator, entrypoint);
-    SCHEDULER.get().unwrap().lock().register_thread(thread);
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let exited = with_scheduler(|scheduler| {
+            scheduler.threads.get_mut(&self.tid).unwrap().detached = true;
+            scheduler.remove_exited(self.tid)
+        });
+        if let Some((_, thread)) = exited {
+            release_thread(thread);
+        }
+    }
 }
 
-pub fn spawn(entrypoint: fn() -> !) {
+fn release_thread(thread: Thread) {
     let mut mapper = lock_memory_mapper();
     let mut frame_allocator = lock_frame_allocator();
+    unsafe { thread.release(&mut *mapper, &mut *frame_allocator) };
+}
 
-    let thread = Thread::create_closure(&mut *mapper, &mut *frame_allocator, entrypoint);
-    SCHEDULER.get().unwrap().lock().register_thread(thread);
+/// Releases detached threads that have exited since the last call. They cannot free their own
+/// stack while still running on it, so this happens the next time a thread is spawned.
+fn reap_detached_threads() {
+    let exited: Vec<Thread> = with_scheduler(|scheduler| {
+        let tids: Vec<ThreadId> = scheduler
+            .threads
+            .values()
+            .filter(|thread| thread.detached && matches!(thread.state, ThreadState::Exited(_)))
+            .map(|thread| thread.tid)
+            .collect();
+        tids.into_iter()
+            .filter_map(|tid| scheduler.remove_exited(tid))
+            .map(|(_, thread)| thread)
+            .collect()
+    });
+    exited.into_iter().for_each(release_thread);
+}
+
+/// Configures a thread before spawning it.
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    name: Option<String>,
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the thread. Unnamed threads are called `thread-<id>`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn spawn(self, entrypoint: impl FnOnce() + Send + 'static) -> JoinHandle {
+        reap_detached_threads();
+        let mut mapper = lock_memory_mapper();
+        let mut frame_allocator = lock_frame_allocator();
+
+        let thread = Thread::create_closure(&mut *mapper, &mut *frame_allocator, entrypoint);
+        self.register(thread)
+    }
+
+    pub fn spawn_user(self, entrypoint: fn()) -> JoinHandle {
+        reap_detached_threads();
+        let mut mapper = lock_memory_mapper();
+        let mut frame_allocator = lock_frame_allocator();
+
+        let thread =
+            Thread::create_userspace_entrypoint(&mut *mapper, &mut *frame_allocator, entrypoint);
+        self.register(thread)
+    }
+
+    fn register(self, mut thread: Thread) -> JoinHandle {
+        let tid = thread.tid;
+        if let Some(name) = self.name {
+            thread.name = name;
+        }
+        thread.priority = self.priority;
+        with_scheduler(|scheduler| scheduler.register_thread(thread));
+        JoinHandle { tid }
+    }
+}
+
+pub fn spawn_user(entrypoint: fn()) -> JoinHandle {
+    Builder::new().spawn_user(entrypoint)
+}
+
+pub fn spawn(entrypoint: impl FnOnce() + Send + 'static) -> JoinHandle {
+    Builder::new().spawn(entrypoint)
 }
 
 pub fn current_thread() -> ThreadId {
     unsafe { ThreadId::from_u64(CURRENT_THREAD.load(Ordering::SeqCst)) }
 }
 
-pub fn schedule() -> Option<Thread> {
-    SCHEDULER.get()?.try_lock()?.schedule()
+/// Makes a thread that blocked itself runnable again. Returns `false` if it was not blocked.
+pub fn wake(tid: ThreadId) -> bool {
+    with_scheduler(|scheduler| scheduler.wake(tid))
 }
 
-pub fn add_paused_thread(
-    stack_frame: &mut InterruptStackFrameValue,
-    regs: &mut Registers,
-    thread: Thread,
-) {
-    let mut scheduler = SCHEDULER.get().unwrap().lock();
+/// Switches away from the current thread until [`wake`] is called for it.
+///
+/// A wake-up that happens before the thread blocks is lost, [`WaitQueue`] avoids that race.
+pub fn block_current() {
+    mark_current_blocked();
+    wait_until_runnable();
+}
+
+/// Puts the current thread to sleep for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let tid = current_thread();
+    // The current tick is already partly over, so one more is needed
+    let until = (time::ticks() + 1).saturating_add(time::duration_to_ticks(duration));
+    with_scheduler(|scheduler| {
+        scheduler.set_state(tid, ThreadState::Sleeping { until });
+        scheduler.sleepers.push((until, tid));
+    });
+    wait_until_runnable();
+}
+
+pub(super) fn mark_current_blocked() {
+    with_scheduler(|scheduler| scheduler.set_state(current_thread(), ThreadState::Blocked));
+}
+
+/// Switches away until the current thread is runnable again.
+pub(super) fn wait_until_runnable() {
+    let tid = current_thread();
+    without_interrupts(|| {
+        // Switches to the idle thread if nothing else can run
+        while with_scheduler(|scheduler| scheduler.state(tid)) != ThreadState::Runnable {
+            reschedule();
+        }
+    });
+}
+
+/// Lets the other runnable threads run before continuing.
+pub fn yield_now() {
+    without_interrupts(|| {
+        reschedule();
+    });
+}
+
+/// Ends the current thread. Its stack and address space are released once it is joined, or
+/// after it is detached.
+pub fn exit(exit_code: i32) -> ! {
+    interrupts::disable();
+    let tid = current_thread();
+    with_scheduler(|scheduler| {
+        scheduler.set_state(tid, ThreadState::Exited(exit_code));
+        scheduler.policy.remove_thread(tid);
+    });
+    fpu::release(tid);
+    EXITED.wake_all();
+    reschedule();
+    unreachable!("Exited thread {} was switched back to", tid.as_u64());
+}
+
+pub fn set_priority(tid: ThreadId, priority: Priority) {
+    with_scheduler(|scheduler| {
+        scheduler.threads.get_mut(&tid).unwrap().priority = priority;
+        scheduler.policy.set_priority(tid, priority);
+    });
+}
+
+pub fn priority(tid: ThreadId) -> Priority {
+    with_scheduler(|scheduler| scheduler.threads[&tid].priority)
+}
 
-    let current_tid =
-        unsafe { ThreadId::from_u64(CURRENT_THREAD.swap(thread.tid.as_u64(), Ordering::SeqCst)) };
-    let current_thread = scheduler.threads.get_mut(&current_tid).unwrap();
-    current_thread.stack_frame.replace(stack_frame.clone());
-    current_thread.regs.replace(regs.clone());
+/// The name of the scheduling policy chosen at boot.
+pub fn policy_name() -> &'static str {
+    with_scheduler(|scheduler| scheduler.policy.name())
+}
+
+/// What [`threads`] reports about each thread, like `ps` does.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub tid: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    /// Whether this is the thread asking.
+    pub running: bool,
+    pub priority: Priority,
+    /// Uptime when the thread was created.
+    pub created_at: Duration,
+    pub ticks_run: u64,
+    pub context_switches: u64,
+    pub last_cpu: Option<u32>,
+}
+
+impl ThreadInfo {
+    /// Column titles matching the `Display` output.
+    pub const HEADER: &'static str =
+        "  TID NAME             STATE      PRIO       CPU TIME   SWITCHES  CPU";
+
+    fn new(thread: &Thread) -> Self {
+        ThreadInfo {
+            tid: thread.tid,
+            name: thread.name.clone(),
+            state: thread.state,
+            running: thread.tid == current_thread(),
+            priority: thread.priority,
+            created_at: time::ticks_to_duration(thread.created_at),
+            ticks_run: thread.ticks_run,
+            context_switches: thread.context_switches,
+            last_cpu: thread.last_cpu,
+        }
+    }
+
+    /// CPU time used so far, measured in whole timer ticks.
+    pub fn cpu_time(&self) -> Duration {
+        time::ticks_to_duration(self.ticks_run)
+    }
+}
+
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            ThreadState::Runnable if self.running => "running",
+            ThreadState::Runnable => "runnable",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Sleeping { .. } => "sleeping",
+            ThreadState::Exited(_) => "exited",
+        };
+        let cpu_time = self.cpu_time();
+        write!(
+            f,
+            "{:>5} {:<16} {:<10} {:>4} {:>9}.{:03}s {:>10}  ",
+            self.tid.as_u64(),
+            self.name,
+            state,
+            self.priority.0,
+            cpu_time.as_secs(),
+            cpu_time.subsec_millis(),
+            self.context_switches,
+        )?;
+        match self.last_cpu {
+            Some(cpu) => write!(f, "{cpu:>3}"),
+            None => write!(f, "{:>3}", "-"),
+        }
+    }
+}
+
+/// How a CPU spent its time since boot, measured in timer ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuStats {
+    pub cpu: u32,
+    pub idle_ticks: u64,
+    pub total_ticks: u64,
+}
+
+impl CpuStats {
+    pub fn idle_time(&self) -> Duration {
+        time::ticks_to_duration(self.idle_ticks)
+    }
 
-    let new_thread = scheduler.threads.get_mut(&thread.tid).unwrap();
-    *stack_frame = new_thread.stack_frame.take().unwrap();
-    *regs = new_thread.regs.take().unwrap();
-    scheduler.queue.push_back(current_tid);
+    pub fn busy_time(&self) -> Duration {
+        time::ticks_to_duration(self.total_ticks - self.idle_ticks)
+    }
+
+    /// The percentage of time spent running threads other than the idle thread since boot.
+    pub fn utilisation(&self) -> u64 {
+        busy_percent(self.idle_ticks, self.total_ticks)
+    }
+
+    /// Like [`utilisation`](Self::utilisation), but only since the `earlier` snapshot.
+    pub fn utilisation_since(&self, earlier: &CpuStats) -> u64 {
+        busy_percent(
+            self.idle_ticks - earlier.idle_ticks,
+            self.total_ticks - earlier.total_ticks,
+        )
+    }
+}
+
+fn busy_percent(idle_ticks: u64, total_ticks: u64) -> u64 {
+    match total_ticks {
+        0 => 0,
+        total => (total - idle_ticks) * 100 / total,
+    }
+}
+
+impl fmt::Display for CpuStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cpu{}: {}% busy, idle for {:?} of {:?}",
+            self.cpu,
+            self.utilisation(),
+            self.idle_time(),
+            time::ticks_to_duration(self.total_ticks)
+        )
+    }
+}
+
+/// Idle accounting for every CPU that runs threads, which so far is only the bootstrap
+/// processor.
+pub fn cpu_stats() -> Vec<CpuStats> {
+    with_scheduler(|scheduler| {
+        scheduler.account();
+        let idle_ticks = scheduler.threads[&scheduler.idle_thread].ticks_run;
+        Vec::from([CpuStats {
+            cpu: current_cpu(),
+            idle_ticks,
+            total_ticks: scheduler.accounted_until,
+        }])
+    })
+}
+
+/// A snapshot of every thread that has not been released yet.
+pub fn threads() -> Vec<ThreadInfo> {
+    with_scheduler(|scheduler| scheduler.threads.values().map(ThreadInfo::new).collect())
+}
+
+/// A snapshot of one thread. `None` if there is no such thread, or if the scheduler is locked,
+/// which can only happen when a panic interrupted it.
+pub fn thread_info(tid: ThreadId) -> Option<ThreadInfo> {
+    let scheduler = SCHEDULER.get()?.try_lock()?;
+    scheduler.threads.get(&tid).map(ThreadInfo::new)
+}
+
+pub fn thread_ids() -> Vec<ThreadId> {
+    match SCHEDULER.get().and_then(|scheduler| scheduler.try_lock()) {
+        Some(scheduler) => scheduler.threads.keys().copied().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Calls `f` with the ID, name and [`thread_context`] of every thread, in order of ID. Nothing
+/// is allocated, so this works while panicking. Returns `false` without calling `f` if the
+/// scheduler is locked.
+pub fn for_each_thread(
+    mut f: impl FnMut(ThreadId, &str, Option<(InterruptStackFrameValue, Registers)>),
+) -> bool {
+    let scheduler = match SCHEDULER.get().and_then(|scheduler| scheduler.try_lock()) {
+        Some(scheduler) => scheduler,
+        None => return false,
+    };
+    for thread in scheduler.threads.values() {
+        f(thread.tid, &thread.name, paused_context(thread));
+    }
+    true
+}
+
+/// The registers a paused thread resumes with: inside the `switch_context` call that switched
+/// away from it for kernel threads, in user mode for user threads. `None` for the running
+/// thread.
+pub fn thread_context(tid: ThreadId) -> Option<(InterruptStackFrameValue, Registers)> {
+    let scheduler = SCHEDULER.get()?.try_lock()?;
+    paused_context(scheduler.threads.get(&tid)?)
+}
+
+fn paused_context(thread: &Thread) -> Option<(InterruptStackFrameValue, Registers)> {
+    if thread.tid == current_thread() {
+        return None;
+    }
+    Some(unsafe { thread.paused_context() })
+}
+
+/// Called from the timer interrupt to wake the threads whose sleep is over.
+pub fn wake_sleepers() {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.lock().wake_sleepers(time::ticks());
+    }
+}
+
+/// Called from the timer interrupt to give the next thread its turn, once the scheduling policy
+/// says the current one has had enough.
+pub fn preempt() {
+    let scheduler = match SCHEDULER.get() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+    let should_switch = {
+        let mut scheduler = scheduler.lock();
+        scheduler.account();
+        let current = current_thread();
+        // The idle thread and threads waiting to become runnable again make way right away
+        if current != scheduler.idle_thread && scheduler.state(current) == ThreadState::Runnable {
+            scheduler.policy.tick(current)
+        } else {
+            true
+        }
+    };
+    if should_switch {
+        reschedule();
+    }
+}
+
+/// Switches to the thread the policy picks, queueing the current one again if it is still
+/// runnable, or to the idle thread if no thread is runnable. Returns `false` without switching
+/// if the current thread is picked again, and otherwise returns once the current thread is
+/// switched back to.
+///
+/// Interrupts must be disabled, as nothing may touch the scheduler between choosing the next
+/// thread and switching to it.
+fn reschedule() -> bool {
+    debug_assert!(!interrupts::are_enabled());
+    let (current_stack_pointer, next_stack_pointer, current_locks, next_locks) = {
+        let mut scheduler = SCHEDULER.get().unwrap().lock();
+        scheduler.account();
+        let current = current_thread();
+        let idle_thread = scheduler.idle_thread;
+        // Blocked, sleeping and exited threads are queued again by whatever makes them runnable
+        if current != idle_thread && scheduler.state(current) == ThreadState::Runnable {
+            scheduler.policy.enqueue(current);
+        }
+        let next = scheduler.policy.pick_next().unwrap_or(idle_thread);
+        if next == current {
+            return false;
+        }
+        CURRENT_THREAD.store(next.as_u64(), Ordering::SeqCst);
+
+        let next_thread = scheduler.threads.get_mut(&next).unwrap();
+        next_thread.context_switches += 1;
+        next_thread.last_cpu = Some(current_cpu());
+        if let Some(stack_top) = next_thread.kernel_stack_top() {
+            gdt::set_kernel_stack(stack_top);
+        }
+        let next_stack_pointer = next_thread.stack_pointer;
+        let next_locks = &next_thread.held_locks as *const HeldLocks;
+        fpu::switch_to(next);
+
+        let current_thread = scheduler.threads.get_mut(&current).unwrap();
+        (
+            &mut current_thread.stack_pointer as *mut u64,
+            next_stack_pointer,
+            &mut current_thread.held_locks as *mut HeldLocks,
+            next_locks,
+        )
+    };
+    unsafe {
+        // The scheduler is unlocked by now, so neither thread is recorded as holding it
+        lockdep::switch_threads(current_locks, next_locks);
+        switch_context(current_stack_pointer, next_stack_pointer);
+    }
+    true
+}
+
+/// What the idle thread runs: waits for interrupts until another thread is runnable, letting
+/// the timer sleep until the first sleeping thread or async timer is due.
+fn idle_loop() {
+    interrupts::disable();
+    loop {
+        if !reschedule() {
+            let sleeper = {
+                let scheduler = SCHEDULER.get().unwrap().lock();
+                scheduler.sleepers.iter().map(|&(until, _)| until).min()
+            };
+            let deadline = match (sleeper, timer::next_deadline()) {
+                (Some(sleeper), Some(timer)) => Some(sleeper.min(timer)),
+                (sleeper, timer) => sleeper.or(timer),
+            };
+            time::idle(deadline);
+        }
+    }
+}
+
+fn current_cpu() -> u32 {
+    CPU_ID.load(Ordering::Relaxed)
+}
+
+/// Moves the FPU registers from `previous`'s save area into the FPU and loads `next`'s state.
+pub(crate) fn swap_fpu_state(previous: Option<ThreadId>, next: ThreadId) {
+    let mut scheduler = SCHEDULER.get().unwrap().lock();
+    if let Some(previous) = previous.and_then(|tid| scheduler.threads.get_mut(&tid)) {
+        unsafe { previous.fpu.save() };
+    }
+    let next = scheduler.threads.get(&next).unwrap();
+    unsafe { next.fpu.restore() };
 }
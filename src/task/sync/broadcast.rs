@@ -0,0 +1,215 @@
+//! Channels that deliver every value to every receiver.
+//!
+//! The channel keeps the last `capacity` values. A receiver that falls further behind misses
+//! the oldest ones, and is told how many it missed.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::WakerQueue;
+use crate::sync::SpinLock;
+
+/// Creates a channel keeping the last `capacity` values, and its first receiver. More receivers
+/// are made with [`Sender::subscribe`].
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let chan = Arc::new(SpinLock::new(
+        "broadcast channel",
+        Chan {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+            first: 0,
+            senders: 1,
+            receivers: 0,
+            waiting: WakerQueue::new(),
+        },
+    ));
+    let sender = Sender { chan };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+struct Chan<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    /// The position of the oldest value in `values` among all values ever sent.
+    first: u64,
+    senders: usize,
+    receivers: usize,
+    waiting: WakerQueue,
+}
+
+impl<T> Chan<T> {
+    fn next(&self) -> u64 {
+        self.first + self.values.len() as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders were dropped and every value has been received.
+    Closed,
+    /// The receiver fell behind and missed this many values. The next call returns the oldest
+    /// value still kept.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+pub struct Sender<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to all current receivers, returning how many there are. Never waits: when
+    /// the channel is full, the oldest value is dropped to make room.
+    pub fn send(&self, value: T) -> usize {
+        without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            if chan.receivers == 0 {
+                return 0;
+            }
+            if chan.values.len() == chan.capacity {
+                chan.values.pop_front();
+                chan.first += 1;
+            }
+            chan.values.push_back(value);
+            chan.waiting.wake_all();
+            chan.receivers
+        })
+    }
+
+    /// A receiver that gets the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            chan.receivers += 1;
+            chan.next()
+        });
+        Receiver {
+            chan: self.chan.clone(),
+            next,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        without_interrupts(|| self.chan.lock().receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        without_interrupts(|| self.chan.lock().senders += 1);
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            chan.senders -= 1;
+            if chan.senders == 0 {
+                chan.waiting.wake_all();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+    /// The position of the next value to receive.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for the next value.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv {
+            receiver: self,
+            waiting: None,
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        without_interrupts(|| {
+            let chan = self.chan.lock();
+            if self.next < chan.first {
+                let missed = chan.first - self.next;
+                self.next = chan.first;
+                return Err(TryRecvError::Lagged(missed));
+            }
+            match chan.values.get((self.next - chan.first) as usize) {
+                Some(value) => {
+                    self.next += 1;
+                    Ok(value.clone())
+                }
+                None if chan.senders == 0 => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        without_interrupts(|| self.chan.lock().receivers -= 1);
+    }
+}
+
+/// The future returned by [`Receiver::recv`].
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+    waiting: Option<u64>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match this.receiver.try_recv() {
+                Ok(value) => return Poll::Ready(Ok(value)),
+                Err(TryRecvError::Lagged(missed)) => {
+                    return Poll::Ready(Err(RecvError::Lagged(missed)))
+                }
+                Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Empty) => {}
+            }
+            let receiver = &this.receiver;
+            let registered = without_interrupts(|| {
+                let mut chan = receiver.chan.lock();
+                // Checked again under the lock, so a value sent meanwhile is not missed
+                if receiver.next < chan.next() || chan.senders == 0 {
+                    return false;
+                }
+                chan.waiting.register(&mut this.waiting, cx.waker());
+                true
+            });
+            if registered {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Recv<'_, T> {
+    fn drop(&mut self) {
+        if self.waiting.is_some() {
+            let chan = &self.receiver.chan;
+            without_interrupts(|| chan.lock().waiting.remove(&mut self.waiting));
+        }
+    }
+}
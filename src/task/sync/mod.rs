@@ -0,0 +1,82 @@
+//! Channels and locks for async tasks, which wait by returning `Poll::Pending` instead of
+//! blocking the thread.
+//!
+//! Signalling never allocates, so interrupt handlers can send on bounded [`mpsc`] channels with
+//! `try_send`, send on [`oneshot`] and [`broadcast`] channels, and call [`Notify::notify_one`].
+//! They must not drop the last handle of a channel though, as that frees it.
+
+pub mod broadcast;
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+
+use alloc::vec::Vec;
+use core::task::Waker;
+
+/// Tasks waiting for something, in the order they started waiting. Each waiting future keeps
+/// the id of its entry, so polling it again only updates its waker.
+struct WakerQueue {
+    wakers: Vec<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl WakerQueue {
+    const fn new() -> Self {
+        WakerQueue {
+            wakers: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    fn register(&mut self, id: &mut Option<u64>, waker: &Waker) {
+        if let Some(entry) = id.and_then(|id| self.wakers.iter_mut().find(|(e, _)| *e == id)) {
+            if !entry.1.will_wake(waker) {
+                entry.1 = waker.clone();
+            }
+            return;
+        }
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.wakers.push((new_id, waker.clone()));
+        *id = Some(new_id);
+    }
+
+    /// Removes the entry, returning whether it was still waiting. `false` means it was woken.
+    fn remove(&mut self, id: &mut Option<u64>) -> bool {
+        let id = match id.take() {
+            Some(id) => id,
+            None => return false,
+        };
+        match self.wakers.iter().position(|&(e, _)| e == id) {
+            Some(index) => {
+                self.wakers.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_waiting(&self, id: Option<u64>) -> bool {
+        id.map_or(false, |id| self.wakers.iter().any(|&(e, _)| e == id))
+    }
+
+    /// Wakes the task that has waited the longest. Returns `false` if there was none.
+    fn wake_one(&mut self) -> bool {
+        if self.wakers.is_empty() {
+            return false;
+        }
+        let (_, waker) = self.wakers.remove(0);
+        waker.wake();
+        true
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Channels with many senders and one receiver, queueing values in the order they were sent.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+use futures_util::stream::Stream;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::WakerQueue;
+use crate::sync::{SpinLock, SpinLockGuard};
+
+/// A channel that holds up to `capacity` values. Sending waits while it is full.
+///
+/// The space is allocated up front, so [`Sender::try_send`] works in interrupt handlers.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    new(VecDeque::with_capacity(capacity), Some(capacity))
+}
+
+/// A channel that holds any number of values. Sending never waits, but allocates.
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    new(VecDeque::new(), None)
+}
+
+fn new<T>(queue: VecDeque<T>, capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(SpinLock::new(
+        "mpsc channel",
+        Chan {
+            queue,
+            capacity,
+            senders: 1,
+            receiver_alive: true,
+            receiver: None,
+            senders_waiting: WakerQueue::new(),
+        },
+    ));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+struct Chan<T> {
+    queue: VecDeque<T>,
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+    /// Senders waiting for room in a full channel.
+    senders_waiting: WakerQueue,
+}
+
+impl<T> Chan<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .map_or(false, |capacity| self.queue.len() >= capacity)
+    }
+
+    fn push(&mut self, value: T) {
+        self.queue.push_back(value);
+        if let Some(receiver) = self.receiver.take() {
+            receiver.wake();
+        }
+    }
+}
+
+/// Locks the channel with interrupts disabled, as interrupt handlers may send on it.
+fn lock<T, R>(chan: &SpinLock<Chan<T>>, f: impl FnOnce(&mut SpinLockGuard<Chan<T>>) -> R) -> R {
+    without_interrupts(|| f(&mut chan.lock()))
+}
+
+/// The receiver was dropped, so the value could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// All senders were dropped and every value has been received.
+    Closed,
+}
+
+pub struct Sender<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room if the channel is full.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            waiting: None,
+        }
+    }
+
+    /// Sends `value` if there is room right now.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        lock(&self.chan, |chan| {
+            if !chan.receiver_alive {
+                Err(TrySendError::Closed(value))
+            } else if chan.is_full() {
+                Err(TrySendError::Full(value))
+            } else {
+                chan.push(value);
+                Ok(())
+            }
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        lock(&self.chan, |chan| !chan.receiver_alive)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        lock(&self.chan, |chan| chan.senders += 1);
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        lock(&self.chan, |chan| {
+            chan.senders -= 1;
+            if chan.senders == 0 {
+                // Lets the receiver see that the channel is closed
+                if let Some(receiver) = chan.receiver.take() {
+                    receiver.wake();
+                }
+            }
+        });
+    }
+}
+
+/// The future returned by [`Sender::send`].
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    /// The entry in the channel's waiting senders.
+    waiting: Option<u64>,
+}
+
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let value = this
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        lock(&this.sender.chan, |chan| {
+            if !chan.receiver_alive {
+                chan.senders_waiting.remove(&mut this.waiting);
+                Poll::Ready(Err(SendError(value)))
+            } else if chan.is_full() {
+                chan.senders_waiting.register(&mut this.waiting, cx.waker());
+                this.value = Some(value);
+                Poll::Pending
+            } else {
+                chan.senders_waiting.remove(&mut this.waiting);
+                chan.push(value);
+                Poll::Ready(Ok(()))
+            }
+        })
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if self.waiting.is_some() {
+            lock(&self.sender.chan, |chan| {
+                // A sender that was woken for the room but gives up passes it on
+                if !chan.senders_waiting.remove(&mut self.waiting) && !chan.is_full() {
+                    chan.senders_waiting.wake_one();
+                }
+            });
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value. Returns `None` once all senders are dropped and every value
+    /// has been received.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        lock(&self.chan, |chan| match chan.queue.pop_front() {
+            Some(value) => {
+                chan.senders_waiting.wake_one();
+                Ok(value)
+            }
+            None if chan.senders == 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        lock(&self.chan, |chan| match chan.queue.pop_front() {
+            Some(value) => {
+                chan.senders_waiting.wake_one();
+                Poll::Ready(Some(value))
+            }
+            None if chan.senders == 0 => Poll::Ready(None),
+            None => {
+                chan.receiver = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Stops accepting values. The ones already sent can still be received.
+    pub fn close(&mut self) {
+        lock(&self.chan, |chan| {
+            chan.receiver_alive = false;
+            chan.senders_waiting.wake_all();
+        });
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
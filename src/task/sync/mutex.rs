@@ -0,0 +1,152 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::WakerQueue;
+use crate::sync::SpinLock;
+
+/// A lock for data shared between tasks, which can be held across `.await`s. Tasks waiting for
+/// it are woken in the order they started waiting.
+pub struct Mutex<T: ?Sized> {
+    state: SpinLock<State>,
+    data: UnsafeCell<T>,
+}
+
+struct State {
+    locked: bool,
+    waiting: WakerQueue,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: SpinLock::new(
+                "async mutex",
+                State {
+                    locked: false,
+                    waiting: WakerQueue::new(),
+                },
+            ),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Waits until the lock is free and takes it.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiting: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.locked {
+                return None;
+            }
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        without_interrupts(|| self.state.lock().locked)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// The future returned by [`Mutex::lock`].
+pub struct Lock<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    waiting: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<MutexGuard<'a, T>> {
+        let this = self.get_mut();
+        let mutex = this.mutex;
+        without_interrupts(|| {
+            let mut state = mutex.state.lock();
+            if state.locked {
+                state.waiting.register(&mut this.waiting, cx.waker());
+                return Poll::Pending;
+            }
+            state.waiting.remove(&mut this.waiting);
+            state.locked = true;
+            Poll::Ready(MutexGuard { mutex })
+        })
+    }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        if self.waiting.is_some() {
+            let mutex = self.mutex;
+            without_interrupts(|| {
+                let mut state = mutex.state.lock();
+                // A task that was woken to take the lock but gives up passes it on
+                if !state.waiting.remove(&mut self.waiting) && !state.locked {
+                    state.waiting.wake_one();
+                }
+            });
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut state = self.mutex.state.lock();
+            state.locked = false;
+            state.waiting.wake_one();
+        });
+    }
+}
@@ -0,0 +1,131 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::WakerQueue;
+use crate::sync::SpinLock;
+
+/// Wakes tasks waiting for an event, without passing any data along.
+///
+/// [`notify_one`](Notify::notify_one) wakes the task that has waited the longest, or, if none
+/// is waiting, lets the next one to wait continue right away. Both can be called from
+/// interrupt handlers.
+pub struct Notify {
+    state: SpinLock<State>,
+}
+
+struct State {
+    /// Set by a `notify_one` that found nobody waiting.
+    permit: bool,
+    /// Bumped by every `notify_waiters`.
+    generation: u64,
+    waiting: WakerQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: SpinLock::new(
+                "notify",
+                State {
+                    permit: false,
+                    generation: 0,
+                    waiting: WakerQueue::new(),
+                },
+            ),
+        }
+    }
+
+    /// Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        let generation = without_interrupts(|| self.state.lock().generation);
+        Notified {
+            notify: self,
+            generation,
+            waiting: None,
+            done: false,
+        }
+    }
+
+    pub fn notify_one(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if !state.waiting.wake_one() {
+                state.permit = true;
+            }
+        });
+    }
+
+    /// Wakes all tasks that are waiting right now. Unlike `notify_one`, it is lost if nobody
+    /// waits.
+    pub fn notify_waiters(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.generation += 1;
+            state.waiting.wake_all();
+        });
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The future returned by [`Notify::notified`]. It counts notifications from
+/// [`notify_waiters`](Notify::notify_waiters) since it was created, even before it is polled.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    generation: u64,
+    waiting: Option<u64>,
+    done: bool,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let notify = this.notify;
+        let notified = without_interrupts(|| {
+            let mut state = notify.state.lock();
+            // Woken by `notify_one` if its entry is gone without a new generation
+            let woken = this.waiting.is_some() && !state.waiting.is_waiting(this.waiting);
+            if woken || state.generation != this.generation {
+                state.waiting.remove(&mut this.waiting);
+                return true;
+            }
+            if state.permit {
+                state.permit = false;
+                return true;
+            }
+            state.waiting.register(&mut this.waiting, cx.waker());
+            false
+        });
+        if notified {
+            this.done = true;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if self.done || self.waiting.is_none() {
+            return;
+        }
+        let notify = self.notify;
+        without_interrupts(|| {
+            let mut state = notify.state.lock();
+            let woken = !state.waiting.remove(&mut self.waiting);
+            // A notification meant for this future goes to the next one instead
+            if woken && state.generation == self.generation && !state.waiting.wake_one() {
+                state.permit = true;
+            }
+        });
+    }
+}
@@ -0,0 +1,104 @@
+//! Channels that carry a single value, e.g. the reply to a request.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::sync::SpinLock;
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(SpinLock::new(
+        "oneshot channel",
+        Chan {
+            value: None,
+            sender_alive: true,
+            receiver_alive: true,
+            receiver: None,
+        },
+    ));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+struct Chan<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+}
+
+/// The sender was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+pub struct Sender<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, or gives it back if the receiver was dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            if !chan.receiver_alive {
+                return Err(value);
+            }
+            chan.value = Some(value);
+            Ok(())
+        })
+        // Dropping `self` wakes the receiver
+    }
+
+    pub fn is_closed(&self) -> bool {
+        without_interrupts(|| !self.chan.lock().receiver_alive)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            chan.sender_alive = false;
+            if let Some(receiver) = chan.receiver.take() {
+                receiver.wake();
+            }
+        });
+    }
+}
+
+/// Waits for the value.
+pub struct Receiver<T> {
+    chan: Arc<SpinLock<Chan<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the value if it has been sent already.
+    pub fn try_recv(&mut self) -> Option<T> {
+        without_interrupts(|| self.chan.lock().value.take())
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        without_interrupts(|| {
+            let mut chan = self.chan.lock();
+            if let Some(value) = chan.value.take() {
+                Poll::Ready(Ok(value))
+            } else if !chan.sender_alive {
+                Poll::Ready(Err(RecvError))
+            } else {
+                chan.receiver = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        without_interrupts(|| self.chan.lock().receiver_alive = false);
+    }
+}
@@ -1,87 +1,253 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use core::mem::size_of;
 use core::ptr::copy_nonoverlapping;
 use core::sync::atomic::{AtomicU64, Ordering};
-use x86_64::registers::control::Cr3;
+use x86_64::instructions::interrupts;
 use x86_64::structures::idt::InterruptStackFrameValue;
 use x86_64::structures::paging::{
-    mapper, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags as Flags, PhysFrame,
-    Size4KiB,
+    mapper, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+    PageTableFlags as Flags, Size4KiB,
 };
 use x86_64::VirtAddr;
 
+use super::context::SwitchFrame;
+use super::policy::Priority;
+use super::scheduler;
+use crate::debug::lockdep::HeldLocks;
+use crate::fpu::FpuState;
 use crate::gdt::GDT;
-use crate::{get_physical_memory_offset, memory};
+use crate::memory::{self, UserAddressSpace, USER_PAGES};
+use crate::syscall::USER_EXIT_STUB;
+use crate::{get_kernel_cr3, time};
 
-#[derive(Debug, Clone, Copy)]
+const USER_CODE_PAGE: usize = 1;
+const USER_EXIT_STUB_PAGE: usize = 2;
+const KERNEL_STACK_PAGES: u64 = 10;
+
+#[derive(Debug)]
 pub struct Thread {
     pub tid: ThreadId,
-    pub stack_frame: Option<InterruptStackFrameValue>,
-    pub regs: Option<Registers>,
+    pub name: String,
+    /// Where the thread's [`SwitchFrame`] is while it is not running.
+    pub stack_pointer: u64,
+    pub fpu: FpuState,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The tick the thread was created at.
+    pub created_at: u64,
+    /// Timer ticks that arrived while the thread was running.
+    pub ticks_run: u64,
+    /// How many times the thread was switched to.
+    pub context_switches: u64,
+    /// The CPU the thread ran on most recently, `None` if it never ran.
+    pub last_cpu: Option<u32>,
+    /// The spinlocks the thread holds while it is not running, for the lock validator.
+    pub held_locks: HeldLocks,
+    /// Nobody is going to join the thread, so it can be released as soon as it exits.
+    pub detached: bool,
+    /// The stack the thread runs on in kernel mode. `None` for the root thread, which keeps the
+    /// boot stack.
+    kernel_stack: Option<Stack>,
+    address_space: Option<UserAddressSpace>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Runnable,
+    /// Waiting to be woken by another thread or an interrupt handler.
+    Blocked,
+    /// Waiting for the timer to reach the given tick.
+    Sleeping { until: u64 },
+    /// The thread has ended and is never scheduled again.
+    Exited(i32),
 }
 
 impl Thread {
+    fn new(kernel_stack: Stack, stack_pointer: VirtAddr) -> Self {
+        let tid = ThreadId::new();
+        Thread {
+            tid,
+            name: format!("thread-{}", tid.as_u64()),
+            stack_pointer: stack_pointer.as_u64(),
+            fpu: FpuState::new(),
+            state: ThreadState::Runnable,
+            priority: Priority::default(),
+            created_at: time::ticks(),
+            ticks_run: 0,
+            context_switches: 0,
+            last_cpu: None,
+            held_locks: HeldLocks::new(),
+            detached: false,
+            kernel_stack: Some(kernel_stack),
+            address_space: None,
+        }
+    }
+
     pub fn create_userspace_entrypoint(
         mapper: &mut OffsetPageTable<'static>,
         frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-        entrypoint: fn() -> !,
+        entrypoint: fn(),
     ) -> Self {
-        let (cr3, user_zero) = memory::allocate_page_table(mapper, frame_allocator);
-        let stack = VirtAddr::new(2 * 4096); // at least 10 pages have been allocated
-        let entrypoint = unsafe {
-            let start_of_code = 4096; // One page in
+        let address_space = memory::allocate_page_table(mapper, frame_allocator);
+        let page_address = |page: usize| VirtAddr::new(page as u64 * Page::<Size4KiB>::SIZE);
+        let user_stack = page_address(USER_PAGES) - 8u64;
+        unsafe {
             copy_nonoverlapping(
                 entrypoint as *const u8,
-                (user_zero.as_u64() + start_of_code + get_physical_memory_offset()) as *mut u8,
+                address_space.page_ptr(USER_CODE_PAGE),
                 4096,
             );
-            VirtAddr::new(start_of_code)
-        };
+            copy_nonoverlapping(
+                USER_EXIT_STUB.as_ptr(),
+                address_space.page_ptr(USER_EXIT_STUB_PAGE),
+                USER_EXIT_STUB.len(),
+            );
+            // Returning from the entrypoint lands in the exit stub
+            let stack_top = address_space.page_ptr(USER_PAGES - 1).add(4096 - 8);
+            stack_top
+                .cast::<u64>()
+                .write(page_address(USER_EXIT_STUB_PAGE).as_u64());
+        }
 
-        Thread {
-            tid: ThreadId::new(),
-            stack_frame: Some(InterruptStackFrameValue {
-                instruction_pointer: entrypoint,
-                code_segment: GDT.1.user_code_selector.0 as u64,
-                cpu_flags: 0x200,
-                stack_pointer: stack,
-                stack_segment: GDT.1.user_data_selector.0 as u64,
-            }),
-            regs: Some(Registers::with_cr3(cr3)),
+        let kernel_stack = Stack::allocate_kernel(KERNEL_STACK_PAGES, mapper, frame_allocator);
+        let mut stack_pointer = kernel_stack.end;
+        unsafe {
+            push(
+                &mut stack_pointer,
+                InterruptStackFrameValue {
+                    instruction_pointer: page_address(USER_CODE_PAGE),
+                    code_segment: GDT.1.user_code_selector.0 as u64,
+                    cpu_flags: 0x200,
+                    stack_pointer: user_stack,
+                    stack_segment: GDT.1.user_data_selector.0 as u64,
+                },
+            );
+            let cr3 = address_space.l4_frame.start_address().as_u64();
+            push(&mut stack_pointer, SwitchFrame::user_entry(cr3));
         }
+
+        let mut thread = Thread::new(kernel_stack, stack_pointer);
+        thread.address_space = Some(address_space);
+        thread
     }
 
-    pub fn create_closure(
+    pub fn create_closure<F>(
         mapper: &mut impl Mapper<Size4KiB>,
         frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-        entrypoint: fn() -> !,
-    ) -> Self {
-        let stack = Stack::allocate_kernel(10, mapper, frame_allocator);
-        let (cr3, _) = Cr3::read();
+        entrypoint: F,
+    ) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let kernel_stack = Stack::allocate_kernel(KERNEL_STACK_PAGES, mapper, frame_allocator);
+        let closure = Box::into_raw(Box::new(entrypoint));
 
-        Thread {
-            tid: ThreadId::new(),
-            stack_frame: Some(InterruptStackFrameValue {
-                instruction_pointer: VirtAddr::new(entrypoint as u64),
-                code_segment: GDT.1.kernel_code_selector.0 as u64,
-                cpu_flags: 0x202,
-                stack_pointer: stack.end,
-                stack_segment: GDT.1.kernel_data_selector.0 as u64,
-            }),
-            regs: Some(Registers::with_cr3(cr3)),
+        let mut stack_pointer = kernel_stack.end;
+        unsafe {
+            // Keeps the stack aligned for the call into the trampoline, and the zero return
+            // address ends backtraces there
+            push(&mut stack_pointer, [0u64; 2]);
+            push(
+                &mut stack_pointer,
+                SwitchFrame::kernel_entry(closure_trampoline::<F> as u64, closure as u64),
+            );
         }
+        Thread::new(kernel_stack, stack_pointer)
     }
 
     pub fn create_root_thread() -> Thread {
         Thread {
             tid: ThreadId::initial(),
-            stack_frame: None,
-            regs: None,
+            name: String::from("main"),
+            stack_pointer: 0,
+            fpu: FpuState::new(),
+            state: ThreadState::Runnable,
+            priority: Priority::default(),
+            created_at: time::ticks(),
+            ticks_run: 0,
+            context_switches: 0,
+            last_cpu: None,
+            held_locks: HeldLocks::new(),
+            detached: false,
+            kernel_stack: None,
+            address_space: None,
+        }
+    }
+
+    /// Top of the stack the CPU switches to when an interrupt arrives in user mode.
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.kernel_stack.as_ref().map(|stack| stack.end)
+    }
+
+    /// The registers the thread had when it was switched away from, in the form the debugger
+    /// expects. User threads only ever enter the kernel through an interrupt, so theirs are the
+    /// user mode registers saved at the top of the kernel stack.
+    ///
+    /// The thread must not be running.
+    pub unsafe fn paused_context(&self) -> (InterruptStackFrameValue, Registers) {
+        let switch_frame = *(self.stack_pointer as *const SwitchFrame);
+        let (address_space, stack_top) = match (&self.address_space, self.kernel_stack_top()) {
+            (Some(address_space), Some(stack_top)) => (address_space, stack_top),
+            _ => {
+                let cr3 = get_kernel_cr3().start_address().as_u64();
+                return switch_frame.to_interrupt_context(self.stack_pointer, cr3);
+            }
+        };
+        let cr3 = address_space.l4_frame.start_address().as_u64();
+        let stack_frame = (stack_top - size_of::<InterruptStackFrameValue>())
+            .as_ptr::<InterruptStackFrameValue>();
+        let entry = stack_frame as u64 - size_of::<SwitchFrame>() as u64;
+        let regs = if self.stack_pointer == entry {
+            // Never ran, only the frames from `create_userspace_entrypoint` are on the stack
+            Registers {
+                cr3,
+                ..Default::default()
+            }
+        } else {
+            // Pushed by the interrupt handler right below the frame the CPU pushed
+            *stack_frame.cast::<Registers>().sub(1)
+        };
+        (*stack_frame, regs)
+    }
+
+    /// Frees the stack and address space of a thread that has exited.
+    ///
+    /// The thread must never run again.
+    pub unsafe fn release(
+        self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        if let Some(stack) = self.kernel_stack {
+            stack.free(mapper, frame_allocator);
+        }
+        if let Some(address_space) = self.address_space {
+            address_space.free(frame_allocator);
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+unsafe fn push<T>(stack_pointer: &mut VirtAddr, value: T) {
+    *stack_pointer -= size_of::<T>();
+    stack_pointer.as_mut_ptr::<T>().write(value);
+}
+
+/// First code a kernel thread runs. Takes ownership of the boxed closure, runs it and ends the
+/// thread.
+extern "C" fn closure_trampoline<F: FnOnce()>(closure: *mut F) -> ! {
+    // Whoever switched here did so with interrupts disabled
+    interrupts::enable();
+    let closure = unsafe { Box::from_raw(closure) };
+    closure();
+    scheduler::exit(0)
+}
+
+#[derive(Debug)]
 pub struct Stack {
     pub end: VirtAddr,
+    start: Page,
 }
 
 impl Stack {
@@ -93,18 +259,14 @@ impl Stack {
         Self::alloc_stack(size_in_pages, mapper, frame_allocator).unwrap()
     }
 
-    //unsafe fn push<T>(&mut self, value: T) {
-    //    self.end -= core::mem::size_of::<T>();
-    //    let ptr: *mut T = self.end.as_mut_ptr();
-    //    ptr.write(value);
-    //}
-
     fn alloc_stack(
         size_in_pages: u64,
         mapper: &mut impl Mapper<Size4KiB>,
         frame_allocator: &mut impl FrameAllocator<Size4KiB>,
     ) -> Result<Stack, mapper::MapToError<Size4KiB>> {
-        static STACK_ALLOC_NEXT: AtomicU64 = AtomicU64::new(0x_5555_5555_0000);
+        // Right above the boot stack, in the part of the address space that user page tables
+        // share with the kernel, so interrupts from user mode can use these stacks
+        static STACK_ALLOC_NEXT: AtomicU64 = AtomicU64::new(0x_007F_8010_0000);
 
         let guard_page_start = STACK_ALLOC_NEXT.fetch_add(
             (size_in_pages + 1) * Page::<Size4KiB>::SIZE,
@@ -126,8 +288,22 @@ impl Stack {
         }
         Ok(Stack {
             end: stack_end.start_address(),
+            start: stack_start,
         })
     }
+
+    unsafe fn free(
+        self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        for page in Page::range(self.start, Page::containing_address(self.end)) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                frame_deallocator.deallocate_frame(frame);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -180,12 +356,3 @@ pub struct Registers {
     pub r14: u64,
     pub r15: u64,
 }
-
-impl Registers {
-    fn with_cr3(cr3: PhysFrame) -> Self {
-        Self {
-            cr3: cr3.start_address().as_u64(),
-            ..Default::default()
-        }
-    }
-}
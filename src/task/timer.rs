@@ -0,0 +1,263 @@
+//! Futures that wait for the timer: [`sleep`], [`timeout`] and [`interval`].
+//!
+//! Waiting futures register their waker in a timing wheel with one slot per tick, wrapping
+//! around every 64 ticks. The timer interrupt only looks at the slots of the ticks that
+//! passed, and wakes the timers in them that are due.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::stream::{Stream, StreamExt};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::sync::SpinLock;
+use crate::time;
+
+const SLOTS: usize = 64;
+
+static WHEEL: SpinLock<TimerWheel> = SpinLock::new("timer wheel", TimerWheel::new());
+
+struct TimerWheel {
+    /// The timers due at tick `t` are in slot `t % SLOTS`.
+    slots: [Vec<Timer>; SLOTS],
+    /// The timers due up to this tick have been woken.
+    processed: u64,
+    next_id: u64,
+}
+
+struct Timer {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+    fired: bool,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Timer> = Vec::new();
+        TimerWheel {
+            slots: [EMPTY; SLOTS],
+            processed: 0,
+            next_id: 0,
+        }
+    }
+
+    fn slot(&mut self, deadline: u64) -> &mut Vec<Timer> {
+        &mut self.slots[deadline as usize % SLOTS]
+    }
+
+    /// Returns `None` if the deadline has already been processed, so the timer would never
+    /// fire.
+    fn insert(&mut self, deadline: u64, waker: Waker) -> Option<u64> {
+        if deadline <= self.processed {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slot(deadline).push(Timer {
+            id,
+            deadline,
+            waker,
+            fired: false,
+        });
+        Some(id)
+    }
+
+    fn remove(&mut self, deadline: u64, id: u64) -> Option<Timer> {
+        let slot = self.slot(deadline);
+        let index = slot.iter().position(|timer| timer.id == id)?;
+        Some(slot.swap_remove(index))
+    }
+
+    /// Wakes the timers due up to `now`. The timers stay in the wheel until their futures
+    /// remove them, so this neither allocates nor frees.
+    fn fire(&mut self, now: u64) {
+        let passed = now.saturating_sub(self.processed).min(SLOTS as u64);
+        for tick in now - passed + 1..=now {
+            for timer in self.slot(tick).iter_mut() {
+                if !timer.fired && timer.deadline <= now {
+                    timer.fired = true;
+                    timer.waker.wake_by_ref();
+                }
+            }
+        }
+        self.processed = self.processed.max(now);
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|timer| !timer.fired)
+            .map(|timer| timer.deadline)
+            .min()
+    }
+}
+
+/// Called from the timer interrupt. Timer futures' wakers are woken from here, so they must be
+/// safe to use in interrupt handlers, like those of the [`Executor`](super::executor::Executor).
+pub(crate) fn wake_expired() {
+    WHEEL.lock().fire(time::ticks());
+}
+
+/// The tick the next timer is due at, up to which the CPU can idle.
+pub(crate) fn next_deadline() -> Option<u64> {
+    without_interrupts(|| WHEEL.lock().next_deadline())
+}
+
+/// Completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::ticks() + time::duration_to_ticks(duration))
+}
+
+/// Completes once [`time::ticks`] has reached `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// The future returned by [`sleep`].
+pub struct Sleep {
+    deadline: u64,
+    /// The id of the timer in the wheel once it has been polled.
+    timer: Option<u64>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn unregister(&mut self) {
+        if let Some(id) = self.timer.take() {
+            // The waker is dropped after the wheel is unlocked
+            let timer = without_interrupts(|| WHEEL.lock().remove(self.deadline, id));
+            drop(timer);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let registered = without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            match self.timer {
+                None => wheel.insert(deadline, cx.waker().clone()),
+                Some(id) => {
+                    let timer = wheel
+                        .slot(deadline)
+                        .iter_mut()
+                        .find(|timer| timer.id == id)?;
+                    if !timer.waker.will_wake(cx.waker()) {
+                        timer.waker = cx.waker().clone();
+                    }
+                    Some(id)
+                }
+            }
+        });
+        match registered {
+            Some(id) => {
+                self.timer = Some(id);
+                Poll::Pending
+            }
+            None => {
+                self.timer = None;
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// The future did not complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, dropping it if it takes longer.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// The future returned by [`timeout`].
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `future` is never moved out of the pinned `Timeout`, and `Sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Yields every `period`, starting one period from now.
+pub fn interval(period: Duration) -> Interval {
+    let period = time::duration_to_ticks(period).max(1);
+    Interval {
+        period,
+        sleep: sleep_until(time::ticks() + period),
+    }
+}
+
+/// A stream of the ticks an [`interval`] fires at. Ticks missed because nobody polled the
+/// stream in time are skipped instead of yielded in a burst.
+pub struct Interval {
+    /// In timer ticks.
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Waits for the next tick of the interval, and returns the tick it was due at.
+    pub async fn tick(&mut self) -> u64 {
+        self.next().await.unwrap()
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u64>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let due = self.sleep.deadline();
+                let now = time::ticks();
+                let missed = (now - due) / self.period;
+                let next = due + (missed + 1) * self.period;
+                self.sleep = sleep_until(next);
+                Poll::Ready(Some(due))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use super::scheduler::{self, current_thread};
+use super::thread::ThreadId;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Threads waiting for something to happen, e.g. for data to arrive. Whoever makes it happen
+/// wakes them, which is also safe from interrupt handlers.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: Mutex<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks the current thread for as long as `condition` returns `true`.
+    ///
+    /// The condition is checked with interrupts disabled right before blocking, so a wake-up
+    /// cannot slip in between.
+    pub fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let waiting = without_interrupts(|| {
+                if !condition() {
+                    return false;
+                }
+                self.waiters.lock().push(current_thread());
+                scheduler::mark_current_blocked();
+                true
+            });
+            if !waiting {
+                return;
+            }
+            scheduler::wait_until_runnable();
+        }
+    }
+
+    /// Wakes the thread that has been waiting the longest. Returns `false` if there was none.
+    pub fn wake_one(&self) -> bool {
+        self.wake_one_with(|_| ())
+    }
+
+    /// Like [`wake_one`](Self::wake_one), but first passes the thread to `handoff`. Interrupts
+    /// stay disabled until the woken thread can run, so whatever `handoff` gives it cannot be
+    /// taken by anybody else first.
+    pub fn wake_one_with(&self, handoff: impl FnOnce(ThreadId)) -> bool {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            while !waiters.is_empty() {
+                // Entries of threads that were already woken some other way are skipped
+                let tid = waiters.remove(0);
+                if scheduler::wake(tid) {
+                    handoff(tid);
+                    return true;
+                }
+            }
+            false
+        })
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        self.wake_all_with(|_| ())
+    }
+
+    /// Like [`wake_all`](Self::wake_all), passing each woken thread to `handoff` first.
+    pub fn wake_all_with(&self, mut handoff: impl FnMut(ThreadId)) -> usize {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            waiters
+                .drain(..)
+                .filter(|&tid| scheduler::wake(tid))
+                .inspect(|&tid| handoff(tid))
+                .count()
+        })
+    }
+
+    /// Whether any thread is waiting. Only meaningful with interrupts disabled.
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.lock().is_empty()
+    }
+}
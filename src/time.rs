@@ -0,0 +1,254 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the programmable interval timer.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// The PIT's counter is 16 bits wide, 0 stands for 65536.
+const PIT_MAX_COUNT: u64 = 65536;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low then high byte, binary counting.
+const PIT_CHANNEL_0_ACCESS: u8 = 0b0011_0000;
+/// Fires once when the count reaches zero.
+const PIT_MODE_ONE_SHOT: u8 = 0b000 << 1;
+/// Fires every time the count reaches zero, then reloads it.
+const PIT_MODE_PERIODIC: u8 = 0b010 << 1;
+const PIC_1_COMMAND: u16 = 0x20;
+/// Makes the next read of the PIC command port return the interrupt request register.
+const PIC_READ_IRR: u8 = 0x0A;
+
+pub const DEFAULT_HZ: u32 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// PIT input cycles per tick. Starts out as the power-on value, about 18.2 ticks a second.
+static DIVISOR: AtomicU64 = AtomicU64::new(PIT_MAX_COUNT);
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+/// The ticks the pending one-shot interrupt stands for, 0 while the timer is periodic.
+static ONE_SHOT_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Time stamp counter cycles per second, measured against the PIT by [`init`].
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT to fire `hz` times a second. With `tickless`, [`idle`] stops it from
+/// firing every tick while nothing runs.
+pub(crate) fn init(hz: u32, tickless: bool) {
+    let divisor = (PIT_FREQUENCY / hz.max(1) as u64).clamp(1, PIT_MAX_COUNT);
+    DIVISOR.store(divisor, Ordering::SeqCst);
+    TICKLESS.store(tickless, Ordering::SeqCst);
+    interrupts::without_interrupts(|| {
+        start_periodic();
+        calibrate_tsc(divisor);
+    });
+}
+
+/// Counts the TSC cycles in one period of the PIT. Takes up to two ticks.
+fn calibrate_tsc(divisor: u64) {
+    wait_for_reload(divisor);
+    let start = tsc();
+    wait_for_reload(divisor);
+    let cycles = tsc() - start;
+    TSC_HZ.store(cycles * PIT_FREQUENCY / divisor, Ordering::SeqCst);
+}
+
+/// Spins until the periodic counter starts over.
+fn wait_for_reload(divisor: u64) {
+    let mut last = read_count(divisor);
+    loop {
+        let count = read_count(divisor);
+        if count > last {
+            return;
+        }
+        last = count;
+    }
+}
+
+/// Called from the timer interrupt.
+pub(crate) fn tick() {
+    match ONE_SHOT_TICKS.swap(0, Ordering::SeqCst) {
+        0 => {
+            TICKS.fetch_add(1, Ordering::SeqCst);
+        }
+        ticks => {
+            TICKS.fetch_add(ticks, Ordering::SeqCst);
+            start_periodic();
+        }
+    }
+}
+
+/// Number of ticks since boot. It never goes backwards, and keeps counting while the timer is
+/// stopped by [`idle`].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// The actual timer frequency, which can be slightly off from the one asked for.
+pub fn hz() -> u64 {
+    PIT_FREQUENCY / DIVISOR.load(Ordering::Relaxed)
+}
+
+pub fn nanos_per_tick() -> u64 {
+    DIVISOR.load(Ordering::Relaxed) * 1_000_000_000 / PIT_FREQUENCY
+}
+
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * nanos_per_tick())
+}
+
+/// The time stamp counter, for measuring spans much shorter than a tick.
+pub fn tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn tsc_to_duration(cycles: u64) -> Duration {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => Duration::ZERO,
+        hz => Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz as u128) as u64),
+    }
+}
+
+/// The number of ticks that take at least `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    let nanos_per_tick = nanos_per_tick() as u128;
+    let ticks = (nanos + nanos_per_tick - 1) / nanos_per_tick;
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Waits for an interrupt when there is nothing to run. In tickless mode the timer is
+/// reprogrammed to fire only at `deadline`, the next tick anything waits for, or as late as
+/// the PIT allows.
+///
+/// Interrupts must be disabled, and are disabled again on return.
+pub(crate) fn idle(deadline: Option<u64>) {
+    debug_assert!(!interrupts::are_enabled());
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    let max_ticks = PIT_MAX_COUNT / divisor;
+    let ticks = deadline.map_or(max_ticks, |deadline| deadline.saturating_sub(ticks()));
+    let tickless = TICKLESS.load(Ordering::Relaxed);
+    // A tick that is already pending has to be counted as a normal one
+    if !tickless || ticks.min(max_ticks) <= 1 || timer_interrupt_pending() {
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        return;
+    }
+    let ticks = ticks.min(max_ticks);
+
+    // Part of the current tick has already passed
+    let since_last_tick = divisor - read_count(divisor);
+    let count = ticks * divisor - since_last_tick;
+    ONE_SHOT_TICKS.store(ticks, Ordering::SeqCst);
+    start_one_shot(count);
+
+    interrupts::enable_and_hlt();
+    interrupts::disable();
+
+    let remaining = read_count(count);
+    // If the timer has run out, its interrupt is pending and counts the ticks
+    if ONE_SHOT_TICKS.load(Ordering::SeqCst) != 0 && remaining != 0 {
+        // Woken by another interrupt before the timer fired: count the ticks that did pass,
+        // and let the timer fire again at the end of the current one
+        let elapsed = since_last_tick + count - remaining;
+        TICKS.fetch_add(elapsed / divisor, Ordering::SeqCst);
+        ONE_SHOT_TICKS.store(1, Ordering::SeqCst);
+        start_one_shot(divisor - elapsed % divisor);
+    }
+}
+
+fn start_periodic() {
+    program(PIT_MODE_PERIODIC, DIVISOR.load(Ordering::Relaxed));
+}
+
+fn start_one_shot(count: u64) {
+    program(PIT_MODE_ONE_SHOT, count);
+}
+
+fn program(mode: u8, count: u64) {
+    // The count register takes 0 for the largest count
+    let count = (count.clamp(1, PIT_MAX_COUNT) % PIT_MAX_COUNT) as u16;
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_CHANNEL_0_ACCESS | mode);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+    }
+}
+
+fn timer_interrupt_pending() -> bool {
+    let mut command = Port::<u8>::new(PIC_1_COMMAND);
+    unsafe {
+        command.write(PIC_READ_IRR);
+        command.read() & 1 != 0
+    }
+}
+
+/// The cycles left until the counter reaches zero, given what it counts down from. A one-shot
+/// counter keeps going past zero, which counts as zero left.
+fn read_count(started_at: u64) -> u64 {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+    let count = unsafe {
+        // Latches the current count of channel 0
+        command.write(0);
+        let low = channel.read() as u64;
+        let high = channel.read() as u64;
+        match high << 8 | low {
+            0 => PIT_MAX_COUNT,
+            count => count,
+        }
+    };
+    if count > started_at {
+        0
+    } else {
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn duration_rounds_up_to_whole_ticks() {
+        let nanos_per_tick = nanos_per_tick();
+        assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+        assert_eq!(duration_to_ticks(Duration::from_nanos(nanos_per_tick)), 1);
+        assert_eq!(
+            duration_to_ticks(Duration::from_nanos(nanos_per_tick + 1)),
+            2
+        );
+        assert_eq!(
+            duration_to_ticks(Duration::from_secs(1)),
+            DEFAULT_HZ as u64 + 1
+        );
+    }
+
+    #[test_case]
+    fn tsc_measures_short_spans() {
+        let start = tsc();
+        let start_ticks = ticks();
+        while ticks() < start_ticks + 2 {
+            core::hint::spin_loop();
+        }
+        let elapsed = tsc_to_duration(tsc() - start);
+        // At least one whole tick passed, and not much more than two
+        assert!(elapsed >= ticks_to_duration(1));
+        assert!(elapsed <= ticks_to_duration(4));
+    }
+
+    #[test_case]
+    fn timer_runs_at_configured_rate() {
+        assert_eq!(hz(), DEFAULT_HZ as u64);
+        let start = ticks();
+        while ticks() < start + 2 {
+            core::hint::spin_loop();
+        }
+    }
+}
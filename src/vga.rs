@@ -1,7 +1,8 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::{Mutex, Once};
+use spin::Once;
 
+use crate::sync::SpinLock;
 use crate::{get_framebuffer_address, serial_println};
 
 //TODO: Expose in kernel info
@@ -10,7 +11,7 @@ const VGA_WIDTH: usize = 640;
 const LINE_SIZE: usize = 16;
 
 lazy_static! {
-    pub static ref WRITER: Once<Mutex<Writer>> = Once::new();
+    pub static ref WRITER: Once<SpinLock<Writer>> = Once::new();
 }
 
 pub fn init_vga() {
@@ -24,10 +25,15 @@ pub fn init_vga() {
         }
     }
     WRITER.call_once(|| {
-        Mutex::new(Writer {
-            column_position: 0,
-            buffer: unsafe { core::slice::from_raw_parts_mut(vga_base, VGA_WIDTH * VGA_HEIGHT) },
-        })
+        SpinLock::new(
+            "vga writer",
+            Writer {
+                column_position: 0,
+                buffer: unsafe {
+                    core::slice::from_raw_parts_mut(vga_base, VGA_WIDTH * VGA_HEIGHT)
+                },
+            },
+        )
     });
 }
 
@@ -60,11 +66,14 @@ impl Writer {
                 let row = BUFFER_HEIGHT - 1;
                 let col = self.column_position;
 
+                // The font only covers ASCII
+                let bm = font8x8::legacy::BASIC_LEGACY
+                    .get(byte as usize)
+                    .unwrap_or(&font8x8::legacy::BASIC_LEGACY[b'?' as usize]);
                 for y in 16 * row..16 * (row + 1) {
                     for x in 16 * col..16 * (col + 1) {
                         let bm_y = y % 16 / 2;
                         let bm_x = x % 16 / 2;
-                        let bm = font8x8::legacy::BASIC_LEGACY[byte as usize];
                         const W: u32 = 0xFFFFFF;
                         self.buffer[x + y * VGA_WIDTH] = (bm[bm_y] & (1 << bm_x)) as u32 * W;
                     }
@@ -88,6 +97,33 @@ impl Writer {
             self.buffer[col] = 0;
         }
     }
+
+    fn redraw_line(&mut self, line: &str, cursor: Option<usize>) {
+        // Scroll sideways so the cursor stays on screen
+        let start = match cursor {
+            Some(cursor) if cursor >= BUFFER_WIDTH => cursor + 1 - BUFFER_WIDTH,
+            _ => 0,
+        };
+        self.clear_row(BUFFER_HEIGHT - 1);
+        self.column_position = 0;
+        for c in line.chars().skip(start).take(BUFFER_WIDTH) {
+            match c {
+                ' '..='~' => self.write_byte(c as u8),
+                _ => self.write_byte(b'?'),
+            }
+        }
+        if let Some(cursor) = cursor {
+            self.invert_cell(BUFFER_HEIGHT - 1, cursor - start);
+        }
+    }
+
+    fn invert_cell(&mut self, row: usize, col: usize) {
+        for y in LINE_SIZE * row..LINE_SIZE * (row + 1) {
+            for x in LINE_SIZE * col..LINE_SIZE * (col + 1) {
+                self.buffer[x + y * VGA_WIDTH] ^= 0xFFFFFF;
+            }
+        }
+    }
 }
 
 impl fmt::Write for Writer {
@@ -97,6 +133,84 @@ impl fmt::Write for Writer {
     }
 }
 
+const PANIC_GLYPH_SIZE: usize = 8;
+const PANIC_BACKGROUND: u32 = 0x800000;
+const PANIC_FOREGROUND: u32 = 0xFFFFFF;
+
+/// Takes over the whole framebuffer to show a crash report, using the unscaled font so that a
+/// full register dump fits. Returns `None` if the framebuffer has not been set up yet.
+///
+/// Writes to the framebuffer directly instead of going through [`WRITER`], which the panicking
+/// code may be holding.
+pub fn panic_screen() -> Option<PanicScreen> {
+    WRITER.get()?;
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(
+            get_framebuffer_address() as *mut u32,
+            VGA_WIDTH * VGA_HEIGHT,
+        )
+    };
+    buffer.fill(PANIC_BACKGROUND);
+    Some(PanicScreen {
+        row: 0,
+        column: 0,
+        buffer,
+    })
+}
+
+pub struct PanicScreen {
+    row: usize,
+    column: usize,
+    buffer: &'static mut [u32],
+}
+
+impl PanicScreen {
+    const ROWS: usize = VGA_HEIGHT / PANIC_GLYPH_SIZE;
+    const COLUMNS: usize = VGA_WIDTH / PANIC_GLYPH_SIZE;
+
+    fn write_byte(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.row += 1;
+            self.column = 0;
+            return;
+        }
+        if self.column >= Self::COLUMNS {
+            self.row += 1;
+            self.column = 0;
+        }
+        // Whatever does not fit is still on serial
+        if self.row >= Self::ROWS {
+            return;
+        }
+
+        let glyph = font8x8::legacy::BASIC_LEGACY[byte as usize];
+        for (dy, bits) in glyph.iter().enumerate() {
+            let y = self.row * PANIC_GLYPH_SIZE + dy;
+            for dx in 0..PANIC_GLYPH_SIZE {
+                let x = self.column * PANIC_GLYPH_SIZE + dx;
+                self.buffer[x + y * VGA_WIDTH] = if bits & (1 << dx) != 0 {
+                    PANIC_FOREGROUND
+                } else {
+                    PANIC_BACKGROUND
+                };
+            }
+        }
+        self.column += 1;
+    }
+}
+
+impl fmt::Write for PanicScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                0x20..=0x7E | b'\n' => self.write_byte(byte),
+                _ => self.write_byte(b'?'),
+            }
+        }
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga::_print(format_args!($($arg)*)));
@@ -117,6 +231,17 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Replaces the line being written with `line`, for editing it. A cursor is drawn at column
+/// `cursor` of `line`. Lines wider than the screen are scrolled so the cursor stays visible.
+///
+/// Further output continues after the line.
+pub fn redraw_line(line: &str, cursor: Option<usize>) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        WRITER.get().unwrap().lock().redraw_line(line, cursor);
+    });
+}
+
 #[cfg(test)]
 mod tests {
     #[test_case]
@@ -130,4 +255,14 @@ mod tests {
             println!("a line");
         }
     }
+
+    #[test_case]
+    fn lines_are_redrawn() {
+        super::redraw_line("> a line", Some(3));
+        // Wider than the screen, with the cursor at the end
+        super::redraw_line(&"x".repeat(100), Some(100));
+        super::redraw_line("> £ é ä", Some(4));
+        super::redraw_line("> done", None);
+        println!();
+    }
 }
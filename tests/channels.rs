@@ -0,0 +1,241 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::tests::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    os::init(boot_info);
+
+    test_main();
+    os::hlt_loop();
+}
+
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::arch::asm;
+    use core::future::Future;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use core::time::Duration;
+
+    use os::interrupts::dynamic;
+    use os::sync::SpinLock;
+    use os::task::executor::{Executor, Spawner};
+    use os::task::sync::{broadcast, mpsc, oneshot, Mutex, Notify};
+    use os::task::timer;
+
+    /// Runs the future returned by `f` on a new executor until it completes.
+    fn run<T, F>(f: impl FnOnce(Spawner) -> F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut executor = Executor::new();
+        let output = Arc::new(SpinLock::new("test output", None));
+        let future = f(executor.spawner());
+        let task = executor.spawn({
+            let output = output.clone();
+            async move { *output.lock() = Some(future.await) }
+        });
+        loop {
+            executor.run_until_stalled();
+            if task.is_finished() {
+                break;
+            }
+            x86_64::instructions::hlt();
+        }
+        let output = output.lock().take();
+        output.unwrap()
+    }
+
+    #[test_case]
+    fn bounded_channel_waits_for_room() {
+        let received = run(|spawner| async move {
+            let (sender, mut receiver) = mpsc::channel(2);
+            spawner.spawn(async move {
+                for i in 0..10 {
+                    sender.send(i).await.unwrap();
+                }
+            });
+            let mut received = Vec::new();
+            while let Some(value) = receiver.recv().await {
+                received.push(value);
+            }
+            received
+        });
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test_case]
+    fn full_channel_rejects_try_send() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Empty));
+        drop(receiver);
+        assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+    }
+
+    #[test_case]
+    fn unbounded_channel_closes_with_its_senders() {
+        let sum = run(|spawner| async move {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            for i in 1..=3 {
+                let sender = sender.clone();
+                spawner.spawn(async move {
+                    for _ in 0..100 {
+                        sender.send(i).await.unwrap();
+                    }
+                });
+            }
+            drop(sender);
+            let mut sum = 0;
+            while let Some(value) = receiver.recv().await {
+                sum += value;
+            }
+            sum
+        });
+        assert_eq!(sum, 600);
+    }
+
+    #[test_case]
+    fn oneshot_delivers_reply() {
+        let replies = run(|spawner| async move {
+            let (sender, receiver) = oneshot::channel();
+            spawner.spawn(async move {
+                timer::sleep(Duration::from_millis(20)).await;
+                sender.send(42).unwrap();
+            });
+            let (dropped, abandoned) = oneshot::channel::<u64>();
+            drop(dropped);
+            (receiver.await, abandoned.await)
+        });
+        assert_eq!(replies, (Ok(42), Err(oneshot::RecvError)));
+    }
+
+    #[test_case]
+    fn broadcast_reaches_every_receiver() {
+        let (sender, mut first) = broadcast::channel(4);
+        let mut second = sender.subscribe();
+        assert_eq!(sender.send(1), 2);
+        assert_eq!(first.try_recv(), Ok(1));
+
+        // The second receiver misses the oldest values once the channel wraps around
+        for i in 2..=6 {
+            sender.send(i);
+        }
+        assert_eq!(second.try_recv(), Err(broadcast::TryRecvError::Lagged(2)));
+        assert_eq!(second.try_recv(), Ok(3));
+
+        let received = run(move |_| async move {
+            drop(sender);
+            let mut received = Vec::new();
+            loop {
+                match first.recv().await {
+                    Ok(value) => received.push(value),
+                    Err(broadcast::RecvError::Lagged(missed)) => assert_eq!(missed, 1),
+                    Err(broadcast::RecvError::Closed) => break,
+                }
+            }
+            received
+        });
+        assert_eq!(received, [3, 4, 5, 6]);
+    }
+
+    #[test_case]
+    fn async_mutex_is_held_across_awaits() {
+        let counter = Arc::new(Mutex::new(0));
+        let overlaps = Arc::new(AtomicU64::new(0));
+        run({
+            let counter = counter.clone();
+            |spawner| async move {
+                let tasks: Vec<_> = (0..4)
+                    .map(|_| {
+                        let counter = counter.clone();
+                        let overlaps = overlaps.clone();
+                        spawner.spawn(async move {
+                            let mut guard = counter.lock().await;
+                            let before = *guard;
+                            timer::sleep(Duration::from_millis(10)).await;
+                            if *guard != before {
+                                overlaps.fetch_add(1, Ordering::SeqCst);
+                            }
+                            *guard += 1;
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+                assert_eq!(overlaps.load(Ordering::SeqCst), 0);
+            }
+        });
+        assert_eq!(*counter.try_lock().unwrap(), 4);
+    }
+
+    #[test_case]
+    fn notify_wakes_waiters() {
+        static NOTIFY: Notify = Notify::new();
+        run(|spawner| async move {
+            // A notification with nobody waiting is kept for the next waiter
+            NOTIFY.notify_one();
+            NOTIFY.notified().await;
+
+            let woken = Arc::new(AtomicU64::new(0));
+            let tasks: Vec<_> = (0..3)
+                .map(|_| {
+                    let woken = woken.clone();
+                    spawner.spawn(async move {
+                        NOTIFY.notified().await;
+                        woken.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            timer::sleep(Duration::from_millis(20)).await;
+            NOTIFY.notify_one();
+            timer::sleep(Duration::from_millis(20)).await;
+            assert_eq!(woken.load(Ordering::SeqCst), 1);
+
+            NOTIFY.notify_waiters();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(woken.load(Ordering::SeqCst), 3);
+        });
+    }
+
+    #[test_case]
+    fn interrupt_handler_sends_on_channel() {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let sent = AtomicU64::new(0);
+        let handler = dynamic::register_closure(127, move |_, _| {
+            sender
+                .try_send(sent.fetch_add(1, Ordering::SeqCst))
+                .unwrap();
+        })
+        .unwrap();
+        let received = run(move |_| async move {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                unsafe { asm!("int 127") };
+                received.push(receiver.recv().await.unwrap());
+            }
+            received
+        });
+        assert!(dynamic::unregister(handler));
+        assert_eq!(received, [0, 1, 2]);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    os::tests::test_panic_handler(info);
+}
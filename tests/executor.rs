@@ -0,0 +1,403 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::tests::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    os::init(boot_info);
+
+    test_main();
+    os::hlt_loop();
+}
+
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::future;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use core::time::Duration;
+
+    use os::task::blocking::{block_on, spawn_blocking};
+    use os::task::executor::{self, Cancelled, Executor, JoinHandle, Spawner};
+    use os::task::scheduler;
+    use os::task::timer::{self, Elapsed};
+    use os::task::TaskPriority;
+    use os::time;
+
+    /// Runs the executor, halting while no task is ready, until `task` has finished.
+    fn run_until_finished<T>(executor: &mut Executor, task: &JoinHandle<T>) {
+        loop {
+            executor.run_until_stalled();
+            if task.is_finished() {
+                return;
+            }
+            x86_64::instructions::hlt();
+        }
+    }
+
+    #[test_case]
+    fn join_handle_returns_output() {
+        let mut executor = Executor::new();
+        let result = Arc::new(AtomicU64::new(0));
+        let answer = executor.spawn(async { 6 * 7 });
+        executor.spawn({
+            let result = result.clone();
+            async move { result.store(answer.await.unwrap(), Ordering::SeqCst) }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 42);
+        assert_eq!(executor.task_count(), 0);
+    }
+
+    #[test_case]
+    fn tasks_spawn_subtasks() {
+        async fn leaf(id: u64) -> u64 {
+            id
+        }
+
+        async fn branch(spawner: Spawner, id: u64) -> u64 {
+            let leaves: Vec<_> = (0..3).map(|i| spawner.spawn(leaf(id * 10 + i))).collect();
+            let mut sum = id;
+            for leaf in leaves {
+                sum += leaf.await.unwrap();
+            }
+            sum
+        }
+
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+        let root = executor.spawn(async move {
+            let branches: Vec<_> = (1..=2)
+                .map(|id| spawner.spawn(branch(spawner.clone(), id)))
+                .collect();
+            let mut sum = 0;
+            for branch in branches {
+                sum += branch.await.unwrap();
+            }
+            sum
+        });
+        executor.run_until_stalled();
+        assert!(root.is_finished());
+        assert_eq!(executor.task_count(), 0);
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move { result.store(root.await.unwrap(), Ordering::SeqCst) }
+        });
+        executor.run_until_stalled();
+        // 1 + 10 + 11 + 12 and 2 + 20 + 21 + 22
+        assert_eq!(result.load(Ordering::SeqCst), 34 + 65);
+    }
+
+    #[test_case]
+    fn queue_grows_with_the_tasks() {
+        let mut executor = Executor::new();
+        let done = Arc::new(AtomicU64::new(0));
+        for _ in 0..500 {
+            let done = done.clone();
+            executor.spawn(async move {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(executor.task_count(), 500);
+        executor.run_until_stalled();
+        assert_eq!(done.load(Ordering::SeqCst), 500);
+        assert_eq!(executor.task_count(), 0);
+    }
+
+    #[test_case]
+    fn cancelled_task_is_dropped() {
+        let mut executor = Executor::new();
+        let resource = Arc::new(());
+        let task = executor.spawn({
+            let resource = resource.clone();
+            async move {
+                future::pending::<()>().await;
+                drop(resource);
+            }
+        });
+        executor.run_until_stalled();
+        assert!(!task.is_finished());
+        assert_eq!(Arc::strong_count(&resource), 2);
+
+        task.cancel();
+        executor.run_until_stalled();
+        assert!(task.is_finished());
+        assert_eq!(Arc::strong_count(&resource), 1);
+        assert_eq!(executor.task_count(), 0);
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move {
+                assert_eq!(task.await, Err(Cancelled));
+                result.store(1, Ordering::SeqCst);
+            }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn dropped_handle_detaches_task() {
+        let mut executor = Executor::new();
+        let done = Arc::new(AtomicU64::new(0));
+        drop(executor.spawn({
+            let done = done.clone();
+            async move { done.store(1, Ordering::SeqCst) }
+        }));
+        executor.run_until_stalled();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn sleep_lasts_at_least_the_duration() {
+        let mut executor = Executor::new();
+        let duration = Duration::from_millis(100);
+        let start = time::ticks();
+        let task = executor.spawn(timer::sleep(duration));
+        run_until_finished(&mut executor, &task);
+        assert!(time::ticks() - start >= time::duration_to_ticks(duration));
+    }
+
+    #[test_case]
+    fn sleeping_tasks_wake_in_order() {
+        let mut executor = Executor::new();
+        let order = Arc::new(AtomicU64::new(0));
+        let tasks: Vec<_> = [3, 1, 2]
+            .into_iter()
+            .map(|i| {
+                let order = order.clone();
+                executor.spawn(async move {
+                    // Further apart than one turn of the timer wheel
+                    timer::sleep(Duration::from_millis(400 * i)).await;
+                    order.fetch_add(1, Ordering::SeqCst) + 1 == i
+                })
+            })
+            .collect();
+        let all = executor.spawn(async move {
+            let mut in_order = true;
+            for task in tasks {
+                in_order &= task.await.unwrap();
+            }
+            in_order
+        });
+        run_until_finished(&mut executor, &all);
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move { result.store(all.await.unwrap() as u64, Ordering::SeqCst) }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn timeout_stops_slow_futures() {
+        let mut executor = Executor::new();
+        let task = executor.spawn(async {
+            let fast = timer::timeout(Duration::from_millis(200), async { 5 }).await;
+            let slow = timer::timeout(
+                Duration::from_millis(50),
+                timer::sleep(Duration::from_secs(10)),
+            )
+            .await;
+            (fast, slow)
+        });
+        let start = time::ticks();
+        run_until_finished(&mut executor, &task);
+        assert!(time::ticks() - start < time::duration_to_ticks(Duration::from_secs(1)));
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move {
+                assert_eq!(task.await.unwrap(), (Ok(5), Err(Elapsed)));
+                result.store(1, Ordering::SeqCst);
+            }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn interval_fires_every_period() {
+        let mut executor = Executor::new();
+        let period = Duration::from_millis(50);
+        let task = executor.spawn(async move {
+            let mut interval = timer::interval(period);
+            let mut ticks = Vec::new();
+            for _ in 0..4 {
+                ticks.push(interval.tick().await);
+            }
+            ticks
+        });
+        run_until_finished(&mut executor, &task);
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move {
+                let ticks = task.await.unwrap();
+                let period = time::duration_to_ticks(period);
+                assert!(ticks.windows(2).all(|pair| pair[1] - pair[0] == period));
+                result.store(1, Ordering::SeqCst);
+            }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn higher_priority_tasks_run_first() {
+        let mut executor = Executor::new();
+        let order = Arc::new(AtomicU64::new(0));
+        let tasks: Vec<_> = [TaskPriority::Low, TaskPriority::Normal, TaskPriority::High]
+            .into_iter()
+            .map(|priority| {
+                let order = order.clone();
+                executor.spawn_with_priority(priority, async move {
+                    order.fetch_add(1, Ordering::SeqCst)
+                })
+            })
+            .collect();
+        executor.run_until_stalled();
+
+        let result = Arc::new(AtomicU64::new(0));
+        executor.spawn({
+            let result = result.clone();
+            async move {
+                let mut ran_at = Vec::new();
+                for task in tasks {
+                    ran_at.push(task.await.unwrap());
+                }
+                assert_eq!(ran_at, [2, 1, 0]);
+                result.store(1, Ordering::SeqCst);
+            }
+        });
+        executor.run_until_stalled();
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn busy_task_does_not_starve_lower_priorities() {
+        let mut executor = Executor::new();
+        let spins = Arc::new(AtomicU64::new(0));
+        executor.spawn_with_priority(TaskPriority::High, {
+            let spins = spins.clone();
+            async move {
+                for _ in 0..1000 {
+                    spins.fetch_add(1, Ordering::SeqCst);
+                    executor::yield_now().await;
+                }
+            }
+        });
+        let spins_seen = executor.spawn_with_priority(TaskPriority::Low, {
+            let spins = spins.clone();
+            async move { spins.load(Ordering::SeqCst) }
+        });
+        executor.run_until_stalled();
+        assert!(spins_seen.is_finished());
+        assert_eq!(spins.load(Ordering::SeqCst), 1000);
+        assert!(block_on(spins_seen).unwrap() < 100);
+        // The executor let other threads run in between
+        assert!(executor.stats().yields > 0);
+    }
+
+    #[test_case]
+    fn stats_count_polls_and_wakeups() {
+        let mut executor = Executor::new();
+        let before = executor.stats();
+        let task = executor.spawn(async {
+            for _ in 0..3 {
+                executor::yield_now().await;
+            }
+        });
+        executor.run_until_stalled();
+        let stats = task.stats();
+        assert_eq!(stats.polls, 4);
+        assert_eq!(stats.wakeups, 3);
+        assert_eq!(stats.priority, TaskPriority::Normal);
+        assert_eq!(executor.stats().polls - before.polls, 4);
+        assert_eq!(executor.stats().tasks, 0);
+    }
+
+    #[test_case]
+    fn blocking_work_does_not_stall_tasks() {
+        let mut executor = Executor::new();
+        let ticks = Arc::new(AtomicU64::new(0));
+        let counter = executor.spawn({
+            let ticks = ticks.clone();
+            async move {
+                let mut interval = timer::interval(Duration::from_millis(10));
+                loop {
+                    interval.tick().await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        let work = executor.spawn(spawn_blocking(|| {
+            let end = time::uptime() + Duration::from_millis(100);
+            while time::uptime() < end {}
+            scheduler::current_thread()
+        }));
+        run_until_finished(&mut executor, &work);
+        counter.cancel();
+        executor.run_until_stalled();
+        assert!(ticks.load(Ordering::SeqCst) >= 5);
+        assert_ne!(block_on(work).unwrap(), scheduler::current_thread());
+    }
+
+    #[test_case]
+    fn thread_blocks_on_future() {
+        let result = Arc::new(AtomicU64::new(0));
+        let thread = scheduler::spawn({
+            let result = result.clone();
+            move || {
+                let value = block_on(async {
+                    timer::sleep(Duration::from_millis(20)).await;
+                    7
+                });
+                result.store(value, Ordering::SeqCst);
+            }
+        });
+        thread.join();
+        assert_eq!(result.load(Ordering::SeqCst), 7);
+    }
+
+    #[test_case]
+    fn executors_run_on_several_threads() {
+        let spawners: Vec<_> = (0..2)
+            .map(|_| executor::spawn_thread("test executor"))
+            .collect();
+        let tasks: Vec<_> = spawners
+            .iter()
+            .map(|spawner| {
+                spawner.spawn(async {
+                    timer::sleep(Duration::from_millis(20)).await;
+                    scheduler::current_thread()
+                })
+            })
+            .collect();
+        let threads: Vec<_> = tasks
+            .into_iter()
+            .map(|task| block_on(task).unwrap())
+            .collect();
+        assert_ne!(threads[0], threads[1]);
+        assert!(!threads.contains(&scheduler::current_thread()));
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    os::tests::test_panic_handler(info);
+}
@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::tests::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use os::task::policy::Policy;
+use os::Config;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    os::init_with_config(
+        boot_info,
+        Config {
+            scheduling_policy: Policy::FixedPriority,
+            ..Config::default()
+        },
+    );
+
+    test_main();
+    os::hlt_loop();
+}
+
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use os::task::policy::Priority;
+    use os::task::scheduler::{self, Builder};
+    use os::time;
+
+    fn spawn_flag_setter(priority: Priority) -> (scheduler::JoinHandle, Arc<AtomicBool>) {
+        let ran = Arc::new(AtomicBool::new(false));
+        let thread = Builder::new().priority(priority).spawn({
+            let ran = ran.clone();
+            move || ran.store(true, Ordering::SeqCst)
+        });
+        (thread, ran)
+    }
+
+    fn busy_wait_ticks(ticks: u64) {
+        let start = time::ticks();
+        while time::ticks() < start + ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test_case]
+    fn policy_is_selected_at_boot() {
+        assert_eq!(scheduler::policy_name(), "fixed priority");
+    }
+
+    #[test_case]
+    fn lower_priority_waits() {
+        let (thread, ran) = spawn_flag_setter(Priority::LOW);
+        assert_eq!(scheduler::priority(thread.thread_id()), Priority::LOW);
+        busy_wait_ticks(5);
+        assert!(!ran.load(Ordering::SeqCst));
+        // Blocking in join finally lets it run
+        assert_eq!(thread.join(), 0);
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn higher_priority_preempts() {
+        let (thread, ran) = spawn_flag_setter(Priority::HIGH);
+        while !ran.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        assert_eq!(thread.join(), 0);
+    }
+
+    #[test_case]
+    fn equal_priority_takes_turns() {
+        let (thread, ran) = spawn_flag_setter(Priority::NORMAL);
+        while !ran.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        assert_eq!(thread.join(), 0);
+    }
+
+    #[test_case]
+    fn priority_can_change() {
+        let (thread, ran) = spawn_flag_setter(Priority::LOW);
+        busy_wait_ticks(2);
+        assert!(!ran.load(Ordering::SeqCst));
+        scheduler::set_priority(thread.thread_id(), Priority::HIGH);
+        while !ran.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        assert_eq!(thread.join(), 0);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    os::tests::test_panic_handler(info);
+}
@@ -0,0 +1,231 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::tests::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    os::init(boot_info);
+
+    test_main();
+    os::hlt_loop();
+}
+
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    use os::sync::{Barrier, Condvar, Mutex, RwLock, Semaphore, SpinLock};
+    use os::task::scheduler::{self, JoinHandle};
+
+    fn spawn_many(count: usize, f: impl Fn(usize) + Send + Sync + 'static) -> Vec<JoinHandle> {
+        let f = Arc::new(f);
+        (0..count)
+            .map(|i| {
+                let f = f.clone();
+                scheduler::spawn(move || f(i))
+            })
+            .collect()
+    }
+
+    fn join_all(threads: Vec<JoinHandle>) {
+        for thread in threads {
+            assert_eq!(thread.join(), 0);
+        }
+    }
+
+    #[test_case]
+    fn mutex_excludes_other_threads() {
+        static COUNTER: Mutex<u64> = Mutex::new(0);
+
+        let threads = spawn_many(4, |_| {
+            for _ in 0..500 {
+                let mut counter = COUNTER.lock();
+                let value = *counter;
+                // Gives the timer a chance to preempt the thread while it holds the lock
+                for _ in 0..100 {
+                    core::hint::spin_loop();
+                }
+                *counter = value + 1;
+            }
+        });
+        join_all(threads);
+        assert_eq!(*COUNTER.lock(), 2000);
+    }
+
+    #[test_case]
+    fn mutex_blocks_until_unlocked() {
+        let mutex = Arc::new(Mutex::new(()));
+        let locked = Arc::new(AtomicBool::new(false));
+        let guard = mutex.lock();
+        let thread = scheduler::spawn({
+            let (mutex, locked) = (mutex.clone(), locked.clone());
+            move || {
+                let _guard = mutex.lock();
+                locked.store(true, Ordering::SeqCst);
+            }
+        });
+
+        scheduler::sleep(Duration::from_millis(100));
+        assert!(!locked.load(Ordering::SeqCst));
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert_eq!(thread.join(), 0);
+        assert!(locked.load(Ordering::SeqCst));
+        assert!(!mutex.is_locked());
+    }
+
+    #[test_case]
+    fn mutex_is_handed_to_the_waiter() {
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.lock();
+        let thread = scheduler::spawn({
+            let mutex = mutex.clone();
+            move || *mutex.lock() += 1
+        });
+        scheduler::sleep(Duration::from_millis(50));
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            drop(guard);
+            // The waiting thread owns the lock now, even though it has not run yet
+            assert!(mutex.try_lock().is_none());
+        });
+        assert_eq!(thread.join(), 0);
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn rwlock_allows_concurrent_readers() {
+        let lock = Arc::new(RwLock::new(5));
+        let guard = lock.read();
+        let thread = scheduler::spawn({
+            let lock = lock.clone();
+            move || assert_eq!(*lock.read(), 5)
+        });
+        assert_eq!(thread.join(), 0);
+        drop(guard);
+    }
+
+    #[test_case]
+    fn rwlock_writer_waits_for_readers() {
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.read();
+        let writer = scheduler::spawn({
+            let lock = lock.clone();
+            move || *lock.write() = 1
+        });
+
+        scheduler::sleep(Duration::from_millis(100));
+        assert_eq!(*guard, 0);
+        // New readers queue up behind the waiting writer
+        assert!(lock.try_read().is_none());
+        drop(guard);
+        assert_eq!(writer.join(), 0);
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test_case]
+    fn rwlock_counts_correctly() {
+        static LOCK: RwLock<u64> = RwLock::new(0);
+
+        let threads = spawn_many(6, |i| {
+            for _ in 0..200 {
+                if i % 2 == 0 {
+                    *LOCK.write() += 1;
+                } else {
+                    let value = *LOCK.read();
+                    assert!(value <= 600);
+                }
+            }
+        });
+        join_all(threads);
+        assert_eq!(*LOCK.read(), 600);
+    }
+
+    #[test_case]
+    fn semaphore_limits_concurrency() {
+        static SEMAPHORE: Semaphore = Semaphore::new(2);
+        static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+        static MAX_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+        let threads = spawn_many(6, |_| {
+            SEMAPHORE.acquire();
+            let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_ACTIVE.fetch_max(active, Ordering::SeqCst);
+            scheduler::sleep(Duration::from_millis(20));
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            SEMAPHORE.release();
+        });
+        join_all(threads);
+        assert_eq!(MAX_ACTIVE.load(Ordering::SeqCst), 2);
+        assert_eq!(SEMAPHORE.available_permits(), 2);
+    }
+
+    #[test_case]
+    fn condvar_wakes_waiters() {
+        static READY: Mutex<bool> = Mutex::new(false);
+        static CONDVAR: Condvar = Condvar::new();
+
+        let threads = spawn_many(3, |_| {
+            let ready = CONDVAR.wait_while(READY.lock(), |ready| !*ready);
+            assert!(*ready);
+        });
+        scheduler::sleep(Duration::from_millis(100));
+
+        *READY.lock() = true;
+        CONDVAR.notify_all();
+        join_all(threads);
+    }
+
+    #[test_case]
+    fn barrier_releases_all_threads_together() {
+        static BARRIER: Barrier = Barrier::new(4);
+        static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+        static LEADERS: AtomicUsize = AtomicUsize::new(0);
+
+        let threads = spawn_many(3, |i| {
+            scheduler::sleep(Duration::from_millis(20 * i as u64));
+            ARRIVED.fetch_add(1, Ordering::SeqCst);
+            if BARRIER.wait().is_leader() {
+                LEADERS.fetch_add(1, Ordering::SeqCst);
+            }
+            assert_eq!(ARRIVED.load(Ordering::SeqCst), 4);
+        });
+        ARRIVED.fetch_add(1, Ordering::SeqCst);
+        if BARRIER.wait().is_leader() {
+            LEADERS.fetch_add(1, Ordering::SeqCst);
+        }
+        assert_eq!(ARRIVED.load(Ordering::SeqCst), 4);
+        join_all(threads);
+        assert_eq!(LEADERS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn spin_lock_is_shared_between_threads() {
+        static COUNTER: SpinLock<usize> = SpinLock::new("test counter", 0);
+        let threads = spawn_many(4, |_| {
+            for _ in 0..1000 {
+                *COUNTER.lock() += 1;
+            }
+        });
+        join_all(threads);
+        assert_eq!(*COUNTER.lock(), 4000);
+
+        let guard = COUNTER.lock();
+        assert!(COUNTER.try_lock().is_none());
+        drop(guard);
+        assert!(COUNTER.try_lock().is_some());
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    os::tests::test_panic_handler(info);
+}
@@ -18,24 +18,248 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
 }
 
 mod tests {
-    use core::sync::atomic::{AtomicBool, Ordering};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::arch::asm;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use core::time::Duration;
 
-    use os::task::scheduler;
+    use os::syscall::{self, SYS_EXIT, SYS_YIELD};
+    use os::task::scheduler::{self, Builder};
+    use os::task::thread::ThreadState;
+    use os::task::wait_queue::WaitQueue;
+    use os::time;
 
     #[test_case]
     fn simple_kernel_thread() {
-        static OTHER_THREAD: AtomicBool = AtomicBool::new(false);
-        scheduler::spawn(|| {
-            OTHER_THREAD.store(true, Ordering::SeqCst);
-            os::hlt_loop();
+        let other_thread = Arc::new(AtomicBool::new(false));
+        let thread = scheduler::spawn({
+            let other_thread = other_thread.clone();
+            move || other_thread.store(true, Ordering::SeqCst)
         });
-        while !OTHER_THREAD.load(Ordering::SeqCst) {}
+        assert_eq!(thread.join(), 0);
+        assert!(other_thread.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn threads_own_their_closure() {
+        let sum = Arc::new(AtomicU64::new(0));
+        let threads: Vec<_> = (1..=4)
+            .map(|i| {
+                let numbers: Vec<u64> = (0..10).map(|n| n * i).collect();
+                let sum = sum.clone();
+                scheduler::spawn(move || {
+                    sum.fetch_add(numbers.iter().sum(), Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join(), 0);
+        }
+        assert_eq!(sum.load(Ordering::SeqCst), 45 * (1 + 2 + 3 + 4));
+        // Every thread has dropped its clone
+        assert_eq!(Arc::strong_count(&sum), 1);
+    }
+
+    #[test_case]
+    fn kernel_thread_exit_code() {
+        let thread = scheduler::spawn(|| syscall::exit(7));
+        assert_eq!(thread.join(), 7);
+    }
+
+    #[test_case]
+    fn exited_threads_are_released() {
+        // Would run out of memory for stacks if they were never freed
+        for _ in 0..256 {
+            assert_eq!(scheduler::spawn(|| {}).join(), 0);
+        }
+    }
+
+    #[test_case]
+    fn yield_now_runs_other_threads() {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = scheduler::spawn({
+            let done = done.clone();
+            move || done.store(true, Ordering::SeqCst)
+        });
+        // Only yielding lets the other thread run, as interrupts are disabled
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            while !done.load(Ordering::SeqCst) {
+                scheduler::yield_now();
+            }
+        });
+        assert_eq!(thread.join(), 0);
+    }
+
+    #[test_case]
+    fn sleep_lasts_at_least_the_duration() {
+        let duration = Duration::from_millis(200);
+        let start = time::ticks();
+        scheduler::sleep(duration);
+        assert!(time::ticks() - start >= time::duration_to_ticks(duration));
+    }
+
+    #[test_case]
+    fn sleeping_threads_wake_up() {
+        let threads: Vec<_> = (1..=3)
+            .map(|i| scheduler::spawn(move || scheduler::sleep(Duration::from_millis(50 * i))))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join(), 0);
+        }
+    }
+
+    #[test_case]
+    fn blocked_thread_does_not_run() {
+        let progress = Arc::new(AtomicU64::new(0));
+        let thread = scheduler::spawn({
+            let progress = progress.clone();
+            move || {
+                progress.store(1, Ordering::SeqCst);
+                scheduler::block_current();
+                progress.store(2, Ordering::SeqCst);
+            }
+        });
+
+        scheduler::sleep(Duration::from_millis(100));
+        assert_eq!(progress.load(Ordering::SeqCst), 1);
+        assert!(scheduler::wake(thread.thread_id()));
+        assert_eq!(thread.join(), 0);
+        assert_eq!(progress.load(Ordering::SeqCst), 2);
+    }
+
+    #[test_case]
+    fn wait_queue_wakes_waiters() {
+        static QUEUE: WaitQueue = WaitQueue::new();
+        static READY: AtomicBool = AtomicBool::new(false);
+
+        let threads: Vec<_> = (0..3)
+            .map(|_| scheduler::spawn(|| QUEUE.wait_while(|| !READY.load(Ordering::SeqCst))))
+            .collect();
+        scheduler::sleep(Duration::from_millis(100));
+        assert!(threads.iter().all(|thread| !thread.is_finished()));
+
+        READY.store(true, Ordering::SeqCst);
+        assert_eq!(QUEUE.wake_all(), 3);
+        for thread in threads {
+            assert_eq!(thread.join(), 0);
+        }
+    }
+
+    #[test_case]
+    fn threads_are_listed() {
+        let thread = Builder::new()
+            .name("worker")
+            .spawn(|| scheduler::block_current());
+        scheduler::sleep(Duration::from_millis(100));
+
+        let threads = scheduler::threads();
+        let main = threads.iter().find(|info| info.name == "main").unwrap();
+        assert!(main.running);
+        let worker = threads
+            .iter()
+            .find(|info| info.tid == thread.thread_id())
+            .unwrap();
+        assert_eq!(worker.name, "worker");
+        assert_eq!(worker.state, ThreadState::Blocked);
+        assert!(!worker.running);
+
+        assert!(scheduler::wake(thread.thread_id()));
+        assert_eq!(thread.join(), 0);
+    }
+
+    #[test_case]
+    fn cpu_time_is_accounted() {
+        let thread = scheduler::spawn(|| {
+            let start = time::ticks();
+            while time::ticks() < start + 3 {
+                core::hint::spin_loop();
+            }
+            scheduler::block_current();
+        });
+        let tid = thread.thread_id();
+        while scheduler::thread_info(tid).unwrap().state != ThreadState::Blocked {
+            scheduler::sleep(Duration::from_millis(50));
+        }
+
+        let info = scheduler::thread_info(tid).unwrap();
+        assert!(info.ticks_run > 0);
+        assert!(info.cpu_time() > Duration::ZERO);
+        assert!(info.context_switches > 0);
+        assert!(info.last_cpu.is_some());
+        assert!(info.created_at <= time::uptime());
+
+        assert!(scheduler::wake(tid));
+        assert_eq!(thread.join(), 0);
+    }
+
+    #[test_case]
+    fn idle_thread_runs_while_all_threads_wait() {
+        assert!(scheduler::threads().iter().any(|info| info.name == "idle"));
+
+        let before = scheduler::cpu_stats()[0];
+        scheduler::sleep(Duration::from_millis(200));
+        let after = scheduler::cpu_stats()[0];
+        assert!(after.idle_ticks > before.idle_ticks);
+        assert!(after.utilisation_since(&before) < 100);
+    }
+
+    #[test_case]
+    fn busy_thread_uses_all_the_cpu() {
+        let before = scheduler::cpu_stats()[0];
+        let start = time::ticks();
+        while time::ticks() < start + 5 {
+            core::hint::spin_loop();
+        }
+        let after = scheduler::cpu_stats()[0];
+        assert_eq!(after.idle_ticks, before.idle_ticks);
+        assert_eq!(after.utilisation_since(&before), 100);
+    }
+
+    #[test_case]
+    fn simd_registers_are_per_thread() {
+        unsafe { asm!("movq xmm0, {}", in(reg) 0x1234_u64) };
+        scheduler::spawn(|| {
+            unsafe { asm!("movq xmm0, {}", in(reg) 0xdead_u64) };
+        })
+        .join();
+
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value) };
+        assert_eq!(value, 0x1234);
     }
 
     #[test_case]
     fn simple_user() {
         scheduler::spawn_user(|| loop {});
     }
+
+    #[test_case]
+    fn user_thread_returns() {
+        assert_eq!(scheduler::spawn_user(|| {}).join(), 0);
+    }
+
+    #[test_case]
+    fn user_thread_yields() {
+        let thread = scheduler::spawn_user(|| unsafe {
+            asm!("int 0x80", in("rax") SYS_YIELD);
+            asm!(
+                "int 0x80",
+                in("rax") SYS_EXIT,
+                in("rdi") 5,
+                options(noreturn)
+            );
+        });
+        assert_eq!(thread.join(), 5);
+    }
+
+    #[test_case]
+    fn user_thread_exit_code() {
+        let thread = scheduler::spawn_user(|| unsafe {
+            asm!("int 0x80", in("rax") SYS_EXIT, in("rdi") 3, options(noreturn));
+        });
+        assert_eq!(thread.join(), 3);
+    }
 }
 
 #[panic_handler]
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::tests::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use os::Config;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    os::init_with_config(
+        boot_info,
+        Config {
+            timer_hz: 250,
+            tickless: true,
+            ..Config::default()
+        },
+    );
+
+    test_main();
+    os::hlt_loop();
+}
+
+mod tests {
+    use core::time::Duration;
+
+    use os::task::scheduler;
+    use os::time;
+
+    #[test_case]
+    fn timer_runs_at_configured_rate() {
+        assert_eq!(time::hz(), 250);
+        assert_eq!(time::duration_to_ticks(Duration::from_millis(100)), 26);
+    }
+
+    #[test_case]
+    fn ticks_keep_counting_while_idle() {
+        // Nothing else is runnable, so the timer is stopped for most of the sleep
+        for millis in [10, 95, 300] {
+            let duration = Duration::from_millis(millis);
+            let start = time::ticks();
+            scheduler::sleep(duration);
+            let elapsed = time::ticks() - start;
+            let expected = time::duration_to_ticks(duration);
+            assert!(elapsed >= expected, "{elapsed} < {expected}");
+            assert!(elapsed <= expected + 2, "{elapsed} > {expected} + 2");
+        }
+    }
+
+    #[test_case]
+    fn ticks_are_monotonic() {
+        let mut last = time::ticks();
+        let end = last + 20;
+        while last < end {
+            let now = time::ticks();
+            assert!(now >= last);
+            last = now;
+            scheduler::sleep(Duration::from_millis(3));
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    os::tests::test_panic_handler(info);
+}